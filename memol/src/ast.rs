@@ -2,17 +2,31 @@
 use crate::ratio;
 use std::*;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Ast<T> {
     pub ast: T,
     pub bgn: usize,
     pub end: usize,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+pub struct Function<'a> {
+    pub path: sync::Arc<path::Path>,
+    // the range of the name.
+    pub bgn: usize,
+    pub end: usize,
+    // the prefix of the symbols referred from the body.
+    pub ns: String,
+    pub params: Vec<String>,
+    pub body: Box<Ast<Score<'a>>>,
+}
+
+#[derive(Clone, Debug)]
 pub struct Definition<'a> {
-    pub scores: collections::HashMap<String, (path::PathBuf, Box<Ast<Score<'a>>>)>,
-    pub values: collections::HashMap<String, (path::PathBuf, Box<Ast<Score<'a>>>)>,
+    pub scores: collections::HashMap<String, Function<'a>>,
+    pub values: collections::HashMap<String, Function<'a>>,
+    // the default scale, which is defined by "key = ...".
+    pub key: Option<Function<'a>>,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -21,35 +35,101 @@ pub enum Dir {
     Upper,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
+pub enum Arpeggio {
+    Up,
+    Down,
+    // up and down alternately.
+    UpDown,
+    Random,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Ornament {
+    Trill,
+    UpperMordent,
+    LowerMordent,
+}
+
+// the interpolation of a value from the start to the end.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Curve {
+    Linear,
+    // holds the start value until the end.
+    Step,
+    Exponential,
+    Logarithmic,
+    Smooth,
+    // a cubic bezier with the tension, which is linear at 0 and smooth at 1.
+    Bezier(ratio::Ratio),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Articulation {
+    Accent,
+    Staccato,
+    Tenuto,
+}
+
+#[derive(Clone, Debug)]
 pub enum Note<'a> {
     Rest,
-    Note(Dir, char, i64, i64),
-    Value(Option<ratio::Ratio>, Option<ratio::Ratio>),
+    // the symbol, which is "*" for the default scale, and the ordinal.
+    Note(Dir, String, i64, i64),
+    // the start, the end and the curve between them.
+    Value(Option<ratio::Ratio>, Option<ratio::Ratio>, Curve),
     Repeat(cell::Cell<Option<&'a Ast<Note<'a>>>>),
     Octave(i64),
-    OctaveByNote(Dir, char, i64, i64),
+    OctaveByNote(Dir, String, i64, i64),
     Chord(Vec<(Box<Ast<Note<'a>>>, i64)>),
     Group(Vec<(Box<Ast<Note<'a>>>, i64)>),
     Tie(Box<Ast<Note<'a>>>),
+    // the grace note and the main note, which is true if the grace note is before the beat.
+    Grace(Box<Ast<Note<'a>>>, Box<Ast<Note<'a>>>, bool),
+    Ornament(Box<Ast<Note<'a>>>, Ornament),
+    // the note repeated in its span.
+    Ratchet(Box<Ast<Note<'a>>>, i64),
+    Tremolo(Box<Ast<Note<'a>>>, Box<Ast<Note<'a>>>),
+    Articulation(Box<Ast<Note<'a>>>, Articulation),
+    // the velocity of the following notes relative to "mf".
+    Dynamic(ratio::Ratio),
+    // a crescendo if true, which ends at the next dynamic.
+    Hairpin(bool),
     // XXX
     ChordSymbol(String),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Score<'a> {
     Score(Vec<Box<Ast<Note<'a>>>>),
-    Symbol(String),
+    Symbol(String, Vec<Box<Ast<Score<'a>>>>),
+    Let(String, Vec<String>, Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>),
     Parallel(Vec<Box<Ast<Score<'a>>>>),
     Sequence(Vec<Box<Ast<Score<'a>>>>),
-    With(Box<Ast<Score<'a>>>, char, Box<Ast<Score<'a>>>),
+    With(Box<Ast<Score<'a>>>, String, Box<Ast<Score<'a>>>),
     Repeat(Box<Ast<Score<'a>>>, i64),
-    Stretch(Box<Ast<Score<'a>>>, ratio::Ratio),
+    // the endings of the enclosing repeat.
+    Volta(Vec<Box<Ast<Score<'a>>>>),
+    Stretch(Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>),
+    Shift(Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>),
+    Fit(Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>),
     Filter(Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>),
     BinaryOp(Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>, BinaryOp),
     Branch(Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>),
-    Slice(Box<Ast<Score<'a>>>, ratio::Ratio, ratio::Ratio),
+    // one of the scores chosen at random.
+    Choose(Vec<Box<Ast<Score<'a>>>>),
+    Slice(Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>),
     Transpose(Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>),
+    // sets the length of the grace notes and the ornaments.
+    Ornament(Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>),
+    // transposes by the degrees of the scale bound to the symbol.
+    DTranspose(String, Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>),
+    Reverse(Box<Ast<Score<'a>>>),
+    Arpeggio(Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>, Arpeggio),
+    Invert(Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>),
+    Rotate(Box<Ast<Score<'a>>>, Box<Ast<Score<'a>>>),
+    // a syntax error which the parser has recovered from.
+    Error,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -5,48 +5,312 @@ pub use self::eval::*;
 pub use self::score::*;
 pub use self::value::*;
 use crate::ast;
+use crate::misc;
 use crate::random;
 use crate::ratio::Ratio;
 use std::*;
 
+// the range of the source text which a note is generated from.
+#[derive(Clone, Debug)]
+pub struct Location {
+    pub path: sync::Arc<path::Path>,
+    pub bgn: usize,
+    pub end: usize,
+}
+
+// the dynamic and the articulations written on a note, which are combined with "$out.N.velocity"
+// and "$out.N.duration".
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Marks {
+    // relative to "mf".
+    pub dynamic: Option<Ratio>,
+    pub accent: bool,
+    pub staccato: bool,
+    pub tenuto: bool,
+    // the dynamic changes linearly toward the next note in a hairpin.
+    pub hairpin: bool,
+    // expanded at the end of the score, so that a tied note is ornamented as a whole.
+    pub ornament: Option<ast::Ornament>,
+}
+
 #[derive(Clone, Debug)]
 pub struct FlatNote {
     pub t0: Ratio,
     pub t1: Ratio,
     pub nnum: Option<i64>,
+    pub src: Option<Location>,
+    pub marks: Marks,
 }
 
-pub struct Span<'a> {
+// a closure is evaluated lazily in the environment where it is defined.
+#[derive(Copy, Clone)]
+pub struct Closure<'a, 'b> {
+    params: &'a [String],
+    ast: &'a ast::Ast<ast::Score<'a>>,
+    path: &'b sync::Arc<path::Path>,
+    ns: &'b str,
+    scope: &'b Scope<'a, 'b>,
+}
+
+pub struct Scope<'a, 'b> {
+    binds: collections::HashMap<&'a str, Closure<'a, 'b>>,
+    parent: Option<&'b Scope<'a, 'b>>,
+}
+
+pub struct Span<'a, 'b> {
     t0: Ratio,
     dt: Ratio,
     tied: bool,
-    syms: &'a collections::HashMap<char, &'a [FlatNote]>,
-    path: &'a path::Path,
+    // the index and the count of the innermost repeat, if any.
+    repeat: Option<(i64, i64)>,
+    // the length of the grace notes and the ornaments.
+    ornament: Ratio,
+    // the articulations applied to the notes.
+    marks: Marks,
+    syms: &'b collections::HashMap<&'b str, &'b [FlatNote]>,
+    path: &'b sync::Arc<path::Path>,
+    ns: &'b str,
+    scope: &'b Scope<'a, 'b>,
 }
 
 pub struct Generator<'a> {
     rng: &'a random::Generator,
     defs: &'a ast::Definition<'a>,
-    syms: Vec<(char, Vec<FlatNote>)>,
+    syms: Vec<(String, Vec<FlatNote>)>,
+    diags: cell::RefCell<misc::Diagnostics>,
 }
 
 impl<'a> Generator<'a> {
     pub fn new(rng: &'a random::Generator, defs: &'a ast::Definition<'a>) -> Generator<'a> {
         #[rustfmt::skip]
         let c_major = vec![
-            FlatNote { t0: -Ratio::inf(), t1: Ratio::inf(), nnum: Some(69) },
-            FlatNote { t0: -Ratio::inf(), t1: Ratio::inf(), nnum: Some(71) },
-            FlatNote { t0: -Ratio::inf(), t1: Ratio::inf(), nnum: Some(60) },
-            FlatNote { t0: -Ratio::inf(), t1: Ratio::inf(), nnum: Some(62) },
-            FlatNote { t0: -Ratio::inf(), t1: Ratio::inf(), nnum: Some(64) },
-            FlatNote { t0: -Ratio::inf(), t1: Ratio::inf(), nnum: Some(65) },
-            FlatNote { t0: -Ratio::inf(), t1: Ratio::inf(), nnum: Some(67) },
+            FlatNote { t0: -Ratio::inf(), t1: Ratio::inf(), nnum: Some(69), src: None, marks: Marks::default() },
+            FlatNote { t0: -Ratio::inf(), t1: Ratio::inf(), nnum: Some(71), src: None, marks: Marks::default() },
+            FlatNote { t0: -Ratio::inf(), t1: Ratio::inf(), nnum: Some(60), src: None, marks: Marks::default() },
+            FlatNote { t0: -Ratio::inf(), t1: Ratio::inf(), nnum: Some(62), src: None, marks: Marks::default() },
+            FlatNote { t0: -Ratio::inf(), t1: Ratio::inf(), nnum: Some(64), src: None, marks: Marks::default() },
+            FlatNote { t0: -Ratio::inf(), t1: Ratio::inf(), nnum: Some(65), src: None, marks: Marks::default() },
+            FlatNote { t0: -Ratio::inf(), t1: Ratio::inf(), nnum: Some(67), src: None, marks: Marks::default() },
         ];
 
-        Generator {
+        let mut gen = Generator {
             rng: rng,
             defs: defs,
-            syms: vec![('*', c_major)],
+            syms: vec![(String::from("*"), c_major)],
+            diags: cell::RefCell::new(misc::Diagnostics::new()),
+        };
+        gen.check_symbols();
+
+        // "key = ..." replaces the default scale.  the first and the last scales are extended to
+        // the infinity.
+        let key = defs.key.as_ref().map(|f| (f, gen.generate_score_function(f)));
+        if let Some((f, Some(mut key))) = key {
+            key.retain(|e| e.nnum.is_some());
+            let t0 = key.iter().map(|e| e.t0).min();
+            let t1 = key.iter().map(|e| e.t1).max();
+            match (t0, t1) {
+                (Some(t0), Some(t1)) => {
+                    for e in key.iter_mut() {
+                        if e.t0 == t0 {
+                            e.t0 = -Ratio::inf();
+                        }
+                        if e.t1 == t1 {
+                            e.t1 = Ratio::inf();
+                        }
+                    }
+                    gen.syms = vec![(String::from("*"), key)];
+                }
+                _ => {
+                    let err = misc::Error::range(&f.path, f.body.bgn, f.body.end, "key without notes.");
+                    gen.report(err);
+                }
+            }
+        }
+        gen
+    }
+
+    // records an error and lets the generation continue.
+    pub fn report(&self, err: misc::Error) {
+        self.diags.borrow_mut().push(err);
+    }
+
+    pub fn take_diagnostics(&self) -> misc::Diagnostics {
+        mem::take(&mut *self.diags.borrow_mut())
+    }
+
+    // reports the undefined symbols in all the definitions, including the ones which are never
+    // generated.  the symbols are looked up in both of scores and values, since a value can be
+    // passed as a score argument and vice versa.  the definitions are sorted by their positions to
+    // report in a deterministic order.
+    fn check_symbols(&self) {
+        let defs = self.defs.scores.values().chain(self.defs.values.values());
+        let mut fs: Vec<_> = defs.chain(self.defs.key.iter()).collect();
+        fs.sort_by(|x, y| (&x.path, x.bgn).cmp(&(&y.path, y.bgn)));
+        for f in fs.into_iter() {
+            let mut scope: Vec<_> = f.params.iter().map(|p| p.as_str()).collect();
+            self.check_symbols_inner(&f.body, f, &mut scope);
+        }
+    }
+
+    fn check_symbols_inner(
+        &self,
+        score: &'a ast::Ast<ast::Score<'a>>,
+        f: &ast::Function<'a>,
+        scope: &mut Vec<&'a str>,
+    ) {
+        let children: Vec<&ast::Ast<ast::Score<'a>>> = match score.ast {
+            ast::Score::Symbol(ref key, ref args) => {
+                let key_ns = format!("{}{}", f.ns, key);
+                let defined = scope.contains(&key.as_str())
+                    || self.defs.scores.contains_key(&key_ns)
+                    || self.defs.values.contains_key(&key_ns)
+                    || Self::builtin(key, args);
+                if !defined {
+                    self.report(misc::Error::range(&f.path, score.bgn, score.end, "undefined symbol."));
+                }
+                args.iter().map(|a| &**a).collect()
+            }
+            ast::Score::Let(ref key, ref params, ref value, ref body) => {
+                let n = scope.len();
+                scope.extend(params.iter().map(|p| p.as_str()));
+                self.check_symbols_inner(value, f, scope);
+                scope.truncate(n);
+                scope.push(key);
+                self.check_symbols_inner(body, f, scope);
+                scope.truncate(n);
+                Vec::new()
+            }
+            ast::Score::Score(_) | ast::Score::Error => Vec::new(),
+            ast::Score::Parallel(ref ss)
+            | ast::Score::Sequence(ref ss)
+            | ast::Score::Volta(ref ss)
+            | ast::Score::Choose(ref ss) => ss.iter().map(|s| &**s).collect(),
+            ast::Score::Repeat(ref s, _) | ast::Score::Reverse(ref s) => vec![s],
+            ast::Score::With(ref s0, _, ref s1)
+            | ast::Score::Stretch(ref s0, ref s1)
+            | ast::Score::Shift(ref s0, ref s1)
+            | ast::Score::Fit(ref s0, ref s1)
+            | ast::Score::Filter(ref s0, ref s1)
+            | ast::Score::BinaryOp(ref s0, ref s1, _)
+            | ast::Score::Transpose(ref s0, ref s1)
+            | ast::Score::Ornament(ref s0, ref s1)
+            | ast::Score::DTranspose(_, ref s0, ref s1)
+            | ast::Score::Arpeggio(ref s0, ref s1, _)
+            | ast::Score::Invert(ref s0, ref s1)
+            | ast::Score::Rotate(ref s0, ref s1) => vec![s0, s1],
+            ast::Score::Branch(ref s0, ref s1, ref s2) | ast::Score::Slice(ref s0, ref s1, ref s2) => vec![s0, s1, s2],
+        };
+        for s in children.into_iter() {
+            self.check_symbols_inner(s, f, scope);
+        }
+    }
+
+    // resolves a symbol to a local binding or a definition and calls "f" with its body.  returns
+    // None if the symbol is not found.
+    pub fn call<T, F>(
+        &self,
+        score: &'a ast::Ast<ast::Score<'a>>,
+        defs: &'a collections::HashMap<String, ast::Function<'a>>,
+        span: &Span<'a, '_>,
+        f: F,
+    ) -> Result<Option<T>, misc::Error>
+    where
+        F: for<'c> FnOnce(&'a ast::Ast<ast::Score<'a>>, &Span<'a, 'c>) -> Result<T, misc::Error>,
+    {
+        let (key, args) = match score.ast {
+            ast::Score::Symbol(ref key, ref args) => (key, args),
+            _ => return misc::error_range(&span.path, score.bgn, score.end, "syntax error."),
+        };
+
+        let (path, ns, params, body, parent) = match span.scope.get(key) {
+            Some(c) => (c.path, c.ns, c.params, c.ast, Some(c.scope)),
+            None => match defs.get(&format!("{}{}", span.ns, key)) {
+                Some(f) => (&f.path, f.ns.as_str(), &f.params[..], &*f.body, None),
+                None => return Ok(None),
+            },
+        };
+        if params.len() != args.len() {
+            return misc::error_range(&span.path, score.bgn, score.end, "wrong number of arguments.");
+        }
+        let binds = params.iter().zip(args.iter()).map(|(p, a)| {
+            let c = Closure {
+                params: &[],
+                ast: a,
+                path: span.path,
+                ns: span.ns,
+                scope: span.scope,
+            };
+            (p.as_str(), c)
+        });
+        let scope = Scope {
+            binds: binds.collect(),
+            parent: parent,
+        };
+        let span = Span {
+            path: path,
+            ns: ns,
+            scope: &scope,
+            ..*span
+        };
+        Ok(Some(f(body, &span)?))
+    }
+
+    // binds a local definition and calls "f" with the body of "let".
+    pub fn bind<T, F>(&self, score: &'a ast::Ast<ast::Score<'a>>, span: &Span<'a, '_>, f: F) -> Result<T, misc::Error>
+    where
+        F: for<'c> FnOnce(&'a ast::Ast<ast::Score<'a>>, &Span<'a, 'c>) -> Result<T, misc::Error>,
+    {
+        let (key, params, value, body) = match score.ast {
+            ast::Score::Let(ref key, ref params, ref value, ref body) => (key, params, value, body),
+            _ => return misc::error_range(&span.path, score.bgn, score.end, "syntax error."),
+        };
+
+        let c = Closure {
+            params: params,
+            ast: value,
+            path: span.path,
+            ns: span.ns,
+            scope: span.scope,
+        };
+        let scope = Scope {
+            binds: iter::once((key.as_str(), c)).collect(),
+            parent: Some(span.scope),
+        };
+        let span = Span { scope: &scope, ..*span };
+        f(body, &span)
+    }
+}
+
+impl<'a, 'b> Span<'a, 'b> {
+    fn locate<T>(&self, ast: &ast::Ast<T>) -> Option<Location> {
+        Some(Location {
+            path: self.path.clone(),
+            bgn: ast.bgn,
+            end: ast.end,
+        })
+    }
+
+    // the endings are assigned to the last iterations and the first one is repeated before them,
+    // e.g. "repeat 3 [A volta( B, C )]" is "[A B A B A C]".
+    fn volta(&self, endings: &'a [Box<ast::Ast<ast::Score<'a>>>]) -> Option<&'a ast::Ast<ast::Score<'a>>> {
+        let (i, n) = self.repeat?;
+        let j = i - (n - endings.len() as i64);
+        Some(&endings[cmp::max(j, 0) as usize])
+    }
+}
+
+impl<'a, 'b> Scope<'a, 'b> {
+    pub fn new() -> Self {
+        Scope {
+            binds: collections::HashMap::new(),
+            parent: None,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<Closure<'a, 'b>> {
+        match self.binds.get(key) {
+            Some(c) => Some(*c),
+            None => self.parent.and_then(|p| p.get(key)),
         }
     }
 }
@@ -11,6 +11,15 @@ pub struct Evaluator<'a> {
     pub note_len: f64,
     pub note_cnt: f64,
     pub note_nth: f64,
+    pub note_pitch: f64,
+    pub note_t0: f64,
+    pub note_prev_pitch: f64,
+    pub note_next_pitch: f64,
+    pub note_top: f64,
+    pub note_bottom: f64,
+    pub note_velocity: f64,
+    // the pitched notes sorted by the start time and the pitch.
+    onsets: Vec<(ratio::Ratio, i64)>,
 }
 
 impl<'a> Evaluator<'a> {
@@ -20,20 +29,61 @@ impl<'a> Evaluator<'a> {
             note_len: 0.0,
             note_cnt: 0.0,
             note_nth: 0.0,
+            note_pitch: 0.0,
+            note_t0: 0.0,
+            note_prev_pitch: 0.0,
+            note_next_pitch: 0.0,
+            note_top: 0.0,
+            note_bottom: 0.0,
+            note_velocity: 0.0,
+            onsets: Vec::new(),
         }
     }
 
+    // must be called before "set_note()" with the notes of the same score.
+    pub fn set_score(&mut self, ir: &ScoreIr) {
+        self.onsets = ir.iter().filter_map(|g| g.nnum.map(|n| (g.t0, n))).collect();
+        self.onsets.sort();
+    }
+
     pub fn set_note(&mut self, ir: &ScoreIr, f: &FlatNote) {
         // XXX: O(N^2).
         let mut cnt = 0;
+        let mut top = true;
+        let mut bottom = true;
         for g in ir.iter().filter(|g| g.t0 <= f.t0 && f.t0 < g.t1) {
             if ptr::eq(g, f) {
                 self.note_nth = cnt as f64;
             }
             cnt += 1;
+            if let (Some(m), Some(n)) = (g.nnum, f.nnum) {
+                top &= m <= n;
+                bottom &= m >= n;
+            }
         }
         self.note_cnt = cnt as f64;
         self.note_len = (f.t1 - f.t0).to_float();
+        self.note_t0 = f.t0.to_float();
+        self.note_top = if top { 1.0 } else { 0.0 };
+        self.note_bottom = if bottom { 1.0 } else { 0.0 };
+
+        // the highest of the notes starting at the nearest time before and after, or the note
+        // itself if none.
+        let pitch = f.nnum.unwrap_or(0);
+        let i = misc::bsearch_boundary(&self.onsets, |&(t, _)| t < f.t0);
+        let j = misc::bsearch_boundary(&self.onsets, |&(t, _)| t <= f.t0);
+        let prev = i.checked_sub(1).map(|i| self.onsets[i].1);
+        let next = self.onsets.get(j).map(|&(t1, _)| {
+            let k = misc::bsearch_boundary(&self.onsets, |&(t, _)| t <= t1);
+            self.onsets[k - 1].1
+        });
+        self.note_pitch = pitch as f64;
+        self.note_prev_pitch = prev.unwrap_or(pitch) as f64;
+        self.note_next_pitch = next.unwrap_or(pitch) as f64;
+
+        // the velocity by the marks on the default one, until "$out.N.velocity" is evaluated.
+        let dynamic = f.marks.dynamic.map_or(1.0, |v| v.to_float());
+        self.note_velocity = 0.625 * dynamic + if f.marks.accent { 0.125 } else { 0.0 };
     }
 
     pub fn eval(&self, ir: &ValueIr, t: ratio::Ratio) -> f64 {
@@ -43,6 +93,31 @@ impl<'a> Evaluator<'a> {
                 let v = v0 + (v1 - v0) * (t - t0) / (t1 - t0);
                 v.to_float()
             }
+            ValueIr::Curve(t0, t1, v0, v1, curve) => {
+                let t = cmp::min(cmp::max(t, t0), t1);
+                let a = ((t - t0) / (t1 - t0)).to_float();
+                let (v0, v1) = (v0.to_float(), v1.to_float());
+                let a = match curve {
+                    ast::Curve::Linear => a,
+                    ast::Curve::Step => {
+                        if t < t1 {
+                            0.0
+                        } else {
+                            1.0
+                        }
+                    }
+                    // the geometric interpolation and its point reflection.
+                    ast::Curve::Exponential => return v0 * (v1 / v0).powf(a),
+                    ast::Curve::Logarithmic => return v0 + v1 - v0 * (v1 / v0).powf(1.0 - a),
+                    ast::Curve::Smooth => a * a * (3.0 - 2.0 * a),
+                    ast::Curve::Bezier(tension) => {
+                        let c = (1.0 - tension.to_float()) / 3.0;
+                        let b = 1.0 - a;
+                        3.0 * b * b * a * c + 3.0 * b * a * a * (1.0 - c) + a * a * a
+                    }
+                };
+                v0 + (v1 - v0) * a
+            }
             ValueIr::Sequence(t0, ref irs) => {
                 let t = cmp::min(cmp::max(t, t0), irs.last().unwrap().1);
                 let i = misc::bsearch_boundary(&irs, |&(_, t1)| t1 <= t);
@@ -73,11 +148,54 @@ impl<'a> Evaluator<'a> {
                 let elze = self.eval(ir_else, t);
                 cond * then + (1.0 - cond) * elze
             }
+            ValueIr::Call(f, ref irs) => {
+                let arg = |i: usize| self.eval(&irs[i], t);
+                // the phase of the LFOs in cycles.
+                let phase = || arg(0) * t.to_float() + if irs.len() > 1 { arg(1) } else { 0.0 };
+                match f {
+                    Function::Sin => (f64::consts::TAU * phase()).sin(),
+                    Function::Tri => 4.0 * ((phase() + 0.75).rem_euclid(1.0) - 0.5).abs() - 1.0,
+                    Function::Saw => 2.0 * (phase() + 0.5).rem_euclid(1.0) - 1.0,
+                    Function::Square => {
+                        if phase().rem_euclid(1.0) < 0.5 {
+                            1.0
+                        } else {
+                            -1.0
+                        }
+                    }
+                    Function::Min => arg(0).min(arg(1)),
+                    Function::Max => arg(0).max(arg(1)),
+                    Function::Abs => arg(0).abs(),
+                    Function::Floor => arg(0).floor(),
+                    Function::Clamp => arg(0).max(arg(1)).min(arg(2)),
+                    // a result which is not a real number is 0.
+                    Function::Pow => match arg(0).powf(arg(1)) {
+                        v if v.is_nan() => 0.0,
+                        v => v,
+                    },
+                    Function::Exp => arg(0).exp(),
+                    // "A mod 0" is A.
+                    Function::Mod => match (arg(0), arg(1)) {
+                        (a, b) if b != 0.0 => a.rem_euclid(b),
+                        (a, _) => a,
+                    },
+                }
+            }
             ValueIr::Time => t.to_float(),
+            ValueIr::Beat => (t - t.floor()).to_float(),
+            ValueIr::Bar => t.floor() as f64,
             ValueIr::Gauss => self.rng.next_gauss(),
+            ValueIr::Rand => self.rng.next_f64(),
             ValueIr::NoteLen => self.note_len,
             ValueIr::NoteCnt => self.note_cnt,
             ValueIr::NoteNth => self.note_nth,
+            ValueIr::NotePitch => self.note_pitch,
+            ValueIr::NoteTime => t.to_float() - self.note_t0,
+            ValueIr::NotePrevPitch => self.note_prev_pitch,
+            ValueIr::NoteNextPitch => self.note_next_pitch,
+            ValueIr::NoteTop => self.note_top,
+            ValueIr::NoteBottom => self.note_bottom,
+            ValueIr::NoteVelocity => self.note_velocity,
         }
     }
 }
@@ -3,41 +3,67 @@ use super::*;
 use crate::ast;
 use crate::misc;
 use crate::ratio::Ratio;
+use crate::scale;
 use std::*;
 
 pub type ScoreIr = Vec<FlatNote>;
 
+// the limit of the notes which a ratchet, a tremolo or a trill divides a note into.
+const MAX_DIVISIONS: i64 = 1024;
+
 pub struct ScoreState<'a> {
     nnum: i64,
     dir: ast::Dir,
     note: Option<&'a ast::Ast<ast::Note<'a>>>,
-    prev_ties: Vec<(i64, Ratio)>,
-    next_ties: Vec<(i64, Ratio)>,
+    // the dynamic of the following notes.
+    dynamic: Option<Ratio>,
+    // the start time and the first note index of the hairpin, which is a crescendo if true.
+    hairpin: Option<(Ratio, usize, bool, &'a ast::Ast<ast::Note<'a>>)>,
+    // pitch, start time, location and marks of the tied notes.
+    prev_ties: Vec<(i64, Ratio, Option<Location>, Marks)>,
+    next_ties: Vec<(i64, Ratio, Option<Location>, Marks)>,
 }
 
 impl<'a> Generator<'a> {
-    pub fn generate_score(&self, key: &str) -> Result<Option<ScoreIr>, misc::Error> {
-        let syms = self.syms.iter().map(|&(s, ref ns)| (s, &ns[..])).collect();
-        let &(ref path, ref s) = match self.defs.scores.get(key) {
-            Some(v) => v,
-            None => return Ok(None),
-        };
+    // returns None if the score is not defined or fails to be generated.  errors are reported to
+    // the diagnostics.
+    pub fn generate_score(&self, key: &str) -> Option<ScoreIr> {
+        self.generate_score_function(self.defs.scores.get(key)?)
+    }
+
+    pub fn generate_score_function(&self, f: &'a ast::Function<'a>) -> Option<ScoreIr> {
+        let syms = self.syms.iter().map(|&(ref s, ref ns)| (s.as_str(), &ns[..])).collect();
+        if f.params.len() != 0 {
+            self.report(misc::Error::range(&f.path, f.bgn, f.end, "wrong number of arguments."));
+            return None;
+        }
+        let scope = Scope::new();
         let span = Span {
             t0: Ratio::zero(),
             dt: Ratio::one(),
             tied: false,
+            repeat: None,
+            ornament: Ratio::new(1, 32),
+            marks: Marks::default(),
             syms: &syms,
-            path: path,
+            path: &f.path,
+            ns: &f.ns,
+            scope: &scope,
         };
         let mut dst = Vec::new();
-        self.generate_score_inner(s, &span, &mut dst)?;
-        Ok(Some(dst))
+        match self.generate_score_inner(&f.body, &span, &mut dst) {
+            Ok(_) => Some(dst),
+            Err(e) => {
+                self.report(e);
+                None
+            }
+        }
     }
 
     pub fn generate_score_inner(
         &self,
         score: &'a ast::Ast<ast::Score<'a>>,
-        span: &Span<'_>,
+        span: &Span<'a, '_>,
         dst: &mut ScoreIr,
     ) -> Result<Ratio, misc::Error> {
         let end = match score.ast {
@@ -46,34 +72,73 @@ impl<'a> Generator<'a> {
                     nnum: 60,
                     dir: ast::Dir::Lower,
                     note: None,
+                    dynamic: None,
+                    hairpin: None,
                     prev_ties: Vec::new(),
                     next_ties: Vec::new(),
                 };
+                let mut notes = Vec::new();
                 for (i, n) in ns.iter().enumerate() {
                     let span = Span {
                         t0: span.t0 + span.dt * i as i64,
                         ..*span
                     };
-                    self.generate_score_note(n, &span, &mut state, dst)?;
-                    self.resolve_ties(span.t0, &mut state, dst);
+                    self.generate_score_note(n, &span, &mut state, &mut notes)?;
+                    self.resolve_ties(span.t0, &mut state, &mut notes);
                 }
                 let t1 = span.t0 + span.dt * ns.len() as i64;
-                self.resolve_ties(t1, &mut state, dst);
+                self.resolve_ties(t1, &mut state, &mut notes);
+                if let Some((_, _, _, n)) = state.hairpin {
+                    return misc::error_range(&span.path, n.bgn, n.end, "unterminated hairpin.");
+                }
+                for f in notes.into_iter() {
+                    self.expand_ornament(f, score, span, dst)?;
+                }
                 t1
             }
-            ast::Score::Symbol(ref key) => {
-                let &(ref path, ref s) = match self.defs.scores.get(key) {
-                    Some(v) => v,
-                    None => return misc::error(&span.path, score.bgn, "undefined symbol."),
-                };
-                let span = Span { path: path, ..*span };
-                self.generate_score_inner(s, &span, dst)?
+            ast::Score::Symbol(ref key, ref args) => {
+                // the definitions precede the built-in scales.
+                let t = self.call(score, &self.defs.scores, span, |s, span| {
+                    self.generate_score_inner(s, span, dst)
+                })?;
+                match (t, Self::scale_intervals(key, args)) {
+                    (Some(v), _) => v,
+                    (None, Some(intervals)) => {
+                        let mut tmp = Vec::new();
+                        let t = self.generate_score_inner(&args[0], span, &mut tmp)?;
+                        for f in tmp.into_iter() {
+                            let root = match f.nnum {
+                                Some(v) => v,
+                                None => continue,
+                            };
+                            for n in scale::build(intervals, root).into_iter() {
+                                dst.push(FlatNote {
+                                    nnum: Some(n),
+                                    ..f.clone()
+                                });
+                            }
+                        }
+                        t
+                    }
+                    (None, None) => {
+                        self.report(misc::Error::range(
+                            &span.path,
+                            score.bgn,
+                            score.end,
+                            "undefined symbol.",
+                        ));
+                        span.t0
+                    }
+                }
             }
+            ast::Score::Let(..) => self.bind(score, span, |s, span| self.generate_score_inner(s, span, dst))?,
+            // already reported by the parser.
+            ast::Score::Error => span.t0,
             ast::Score::With(ref lhs, ref key, ref rhs) => {
                 let mut dst_rhs = Vec::new();
                 self.generate_score_inner(rhs, &span, &mut dst_rhs)?;
                 let mut syms = span.syms.clone();
-                syms.insert(*key, &dst_rhs[..]);
+                syms.insert(key.as_str(), &dst_rhs[..]);
                 let span = Span { syms: &syms, ..*span };
                 self.generate_score_inner(lhs, &span, dst)?
             }
@@ -94,25 +159,104 @@ impl<'a> Generator<'a> {
             }
             ast::Score::Repeat(ref s, n) => {
                 let mut t = span.t0;
-                for _ in 0..n {
-                    let span = Span { t0: t, ..*span };
+                for i in 0..n {
+                    let span = Span {
+                        t0: t,
+                        repeat: Some((i, n)),
+                        ..*span
+                    };
                     t = self.generate_score_inner(s, &span, dst)?;
                 }
                 t
             }
-            ast::Score::Stretch(ref s, r) => {
+            ast::Score::Volta(ref ss) => match span.volta(ss) {
+                Some(s) => self.generate_score_inner(s, &span, dst)?,
+                None => return misc::error_range(&span.path, score.bgn, score.end, "volta outside repeat."),
+            },
+            ast::Score::Stretch(ref s, ref r) => {
+                let r = self.generate_ratio(r, &span)?;
                 let span = Span {
                     dt: r * span.dt,
                     ..*span
                 };
                 self.generate_score_inner(s, &span, dst)?
             }
+            ast::Score::Branch(ref cond, ref then, ref elze) => {
+                // the condition is evaluated at the beginning of the span.
+                let (ir_cond, _) = self.generate_value_inner(cond, &span)?;
+                let evaluator = Evaluator::new(&self.rng);
+                if evaluator.eval(&ir_cond, span.t0) >= 0.5 {
+                    self.generate_score_inner(then, &span, dst)?
+                } else {
+                    self.generate_score_inner(elze, &span, dst)?
+                }
+            }
+            ast::Score::Choose(ref ss) => {
+                let i = self.rng.next_u64() % ss.len() as u64;
+                self.generate_score_inner(&ss[i as usize], &span, dst)?
+            }
+            ast::Score::Ornament(ref s, ref sr) => {
+                let r = self.generate_ratio(sr, &span)?;
+                if r <= Ratio::zero() {
+                    return misc::error_range(&span.path, sr.bgn, sr.end, "non-positive length.");
+                }
+                let span = Span { ornament: r, ..*span };
+                self.generate_score_inner(s, &span, dst)?
+            }
+            ast::Score::Shift(ref s, ref r) => {
+                // the notes are delayed but the end is not, so that they can overlap the next ones.
+                let r = self.generate_ratio(r, &span)?;
+                let mut tmp = Vec::new();
+                let t = self.generate_score_inner(s, &span, &mut tmp)?;
+                for f in tmp.into_iter() {
+                    dst.push(FlatNote {
+                        t0: f.t0 + r,
+                        t1: f.t1 + r,
+                        ..f
+                    });
+                }
+                t
+            }
+            ast::Score::Fit(ref s, ref sr) => {
+                let r = self.generate_ratio(sr, &span)?;
+                if r <= Ratio::zero() {
+                    return misc::error_range(&span.path, sr.bgn, sr.end, "non-positive length.");
+                }
+                // measures the length and regenerates the score stretched with the same random numbers,
+                // so that the ornaments, the grace notes and the conditions on the time follow the
+                // stretch.  the parts which do not scale with it, e.g. slices, are rescaled afterwards.
+                let state = self.rng.state();
+                let t = self.generate_score_inner(s, &span, &mut Vec::new())?;
+                if t <= span.t0 {
+                    return misc::error_range(&span.path, score.bgn, score.end, "score of zero length.");
+                }
+                self.rng.set_state(state);
+                let span = Span {
+                    dt: span.dt * (r / (t - span.t0)),
+                    ..*span
+                };
+                let mut tmp = Vec::new();
+                let t = self.generate_score_inner(s, &span, &mut tmp)?;
+                if t <= span.t0 {
+                    return misc::error_range(&span.path, score.bgn, score.end, "score of zero length.");
+                }
+                let k = r / (t - span.t0);
+                for f in tmp.into_iter() {
+                    dst.push(FlatNote {
+                        t0: span.t0 + (f.t0 - span.t0) * k,
+                        t1: span.t0 + (f.t1 - span.t0) * k,
+                        ..f
+                    });
+                }
+                span.t0 + r
+            }
             ast::Score::Filter(ref cond, ref then) => {
                 let (ir_cond, _) = self.generate_value_inner(cond, &span)?;
                 let mut ir_then = Vec::new();
                 let t = self.generate_score_inner(then, &span, &mut ir_then)?;
 
                 let mut evaluator = Evaluator::new(&self.rng);
+                evaluator.set_score(&ir_then);
                 for f in ir_then.iter() {
                     evaluator.set_note(&ir_then, f);
                     if evaluator.eval(&ir_cond, f.t0) >= 0.5 {
@@ -121,7 +265,9 @@ impl<'a> Generator<'a> {
                 }
                 t
             }
-            ast::Score::Slice(ref s, t0, t1) => {
+            ast::Score::Slice(ref s, ref t0, ref t1) => {
+                let t0 = self.generate_ratio(t0, &span)?;
+                let t1 = self.generate_ratio(t1, &span)?;
                 // XXX
                 let mut tmp = Vec::new();
                 let span1 = Span {
@@ -142,16 +288,183 @@ impl<'a> Generator<'a> {
                 let t = self.generate_score_inner(ss, &span, &mut ir_s)?;
 
                 let mut evaluator = Evaluator::new(&self.rng);
+                evaluator.set_score(&ir_s);
                 for f in ir_s.iter() {
                     evaluator.set_note(&ir_s, f);
                     let n = evaluator.eval(&ir_n, f.t0).round() as i64;
                     let nnum = f.nnum.map(|e| e + n);
-                    dst.push(FlatNote { nnum, ..*f });
+                    dst.push(FlatNote { nnum, ..f.clone() });
+                }
+                t
+            }
+            ast::Score::DTranspose(ref sym, ref sn, ref ss) => {
+                let (ir_n, _) = self.generate_value_inner(sn, &span)?;
+                let mut ir_s = Vec::new();
+                let t = self.generate_score_inner(ss, &span, &mut ir_s)?;
+                let scale = match span.syms.get(sym.as_str()) {
+                    Some(v) => v,
+                    None => return misc::error_range(&span.path, score.bgn, score.end, "scale does not exist."),
+                };
+
+                let mut evaluator = Evaluator::new(&self.rng);
+                evaluator.set_score(&ir_s);
+                for f in ir_s.iter() {
+                    evaluator.set_note(&ir_s, f);
+                    let n = evaluator.eval(&ir_n, f.t0).round() as i64;
+                    let nnum = match f.nnum {
+                        Some(v) => match Self::transpose_by_degree(scale, f.t0, v, n) {
+                            Some(v) => Some(v),
+                            None => {
+                                return misc::error_range(&span.path, score.bgn, score.end, "scale does not exist.")
+                            }
+                        },
+                        None => None,
+                    };
+                    dst.push(FlatNote { nnum, ..f.clone() });
+                }
+                t
+            }
+            ast::Score::Arpeggio(ref s, ref sd, dir) => {
+                // a constant delay is exact and the others are quantized by the MIDI resolution.
+                let delay = match self.generate_ratio(sd, &span) {
+                    Ok(v) => Ok(v),
+                    Err(_) => Err(self.generate_value_inner(sd, &span)?.0),
+                };
+                let mut tmp = Vec::new();
+                let t = self.generate_score_inner(s, &span, &mut tmp)?;
+                tmp.sort_by_key(|f| f.t0);
+
+                // the notes starting at the same time are a chord.  the delay is limited so that
+                // every note starts before the end of the shortest one.
+                let evaluator = Evaluator::new(&self.rng);
+                let mut up = true;
+                let mut bgn = 0;
+                while bgn < tmp.len() {
+                    let end = bgn + tmp[bgn..].iter().take_while(|f| f.t0 == tmp[bgn].t0).count();
+                    let delay = match delay {
+                        Ok(v) => v,
+                        Err(ref ir) => {
+                            let v = evaluator.eval(ir, tmp[bgn].t0);
+                            Ratio::new((v * crate::TICK as f64).round() as i64, crate::TICK)
+                        }
+                    };
+                    let pitched: Vec<_> = tmp[bgn..end].iter().filter(|f| f.nnum.is_some()).collect();
+                    let delay = match pitched.iter().map(|f| f.t1 - f.t0).min() {
+                        Some(len) => cmp::min(delay, len / pitched.len() as i64),
+                        None => delay,
+                    };
+                    let chord = &mut tmp[bgn..end];
+                    chord.sort_by_key(|f| f.nnum);
+                    match dir {
+                        ast::Arpeggio::Up => (),
+                        ast::Arpeggio::Down => chord.reverse(),
+                        ast::Arpeggio::UpDown => {
+                            if !up {
+                                chord.reverse();
+                            }
+                            up = !up;
+                        }
+                        ast::Arpeggio::Random => {
+                            for i in (1..chord.len()).rev() {
+                                chord.swap(i, (self.rng.next_u64() % (i as u64 + 1)) as usize);
+                            }
+                        }
+                    }
+                    let mut k = 0;
+                    for f in chord.iter() {
+                        if f.nnum.is_none() {
+                            dst.push(f.clone());
+                            continue;
+                        }
+                        dst.push(FlatNote {
+                            t0: f.t0 + delay * k,
+                            ..f.clone()
+                        });
+                        k += 1;
+                    }
+                    bgn = end;
+                }
+                t
+            }
+            ast::Score::Reverse(ref s) => {
+                let mut tmp = Vec::new();
+                let t = self.generate_score_inner(s, &span, &mut tmp)?;
+                for f in tmp.into_iter().rev() {
+                    dst.push(FlatNote {
+                        t0: span.t0 + t - f.t1,
+                        t1: span.t0 + t - f.t0,
+                        ..f
+                    });
+                }
+                t
+            }
+            ast::Score::Invert(ref sn, ref ss) => {
+                // the axis is a note, e.g. "invert {c} ...", or a value.
+                let ir_n = match sn.ast {
+                    ast::Score::Score(ref ns) if ns.iter().any(|n| Self::has_pitch(n)) => {
+                        let mut ir_n = Vec::new();
+                        self.generate_score_inner(sn, &span, &mut ir_n)?;
+                        match Self::pitch_value(&ir_n) {
+                            Some(v) => v,
+                            None => return misc::error_range(&span.path, sn.bgn, sn.end, "axis without notes."),
+                        }
+                    }
+                    _ => self.generate_value_inner(sn, &span)?.0,
+                };
+                let mut ir_s = Vec::new();
+                let t = self.generate_score_inner(ss, &span, &mut ir_s)?;
+
+                // mirrors the pitches around the axis, which can be between two notes.
+                let mut evaluator = Evaluator::new(&self.rng);
+                evaluator.set_score(&ir_s);
+                for f in ir_s.iter() {
+                    evaluator.set_note(&ir_s, f);
+                    let n = (evaluator.eval(&ir_n, f.t0) * 2.0).round() as i64;
+                    let nnum = f.nnum.map(|e| n - e);
+                    dst.push(FlatNote { nnum, ..f.clone() });
+                }
+                t
+            }
+            ast::Score::Rotate(ref s, ref r) => {
+                let r = self.generate_ratio(r, &span)?;
+                let mut tmp = Vec::new();
+                let t = self.generate_score_inner(s, &span, &mut tmp)?;
+                let len = t - span.t0;
+                if len <= Ratio::zero() {
+                    dst.extend(tmp);
+                    return Ok(t);
+                }
+
+                // shifts the notes later cyclically and splits the ones across the end.
+                let r = r - len * (r / len).floor();
+                for f in tmp.into_iter() {
+                    let t0 = f.t0 + r;
+                    let t1 = f.t1 + r;
+                    if t0 >= t {
+                        dst.push(FlatNote {
+                            t0: t0 - len,
+                            t1: t1 - len,
+                            ..f
+                        });
+                    } else if t1 > t {
+                        dst.push(FlatNote {
+                            t0: t0,
+                            t1: t,
+                            ..f.clone()
+                        });
+                        dst.push(FlatNote {
+                            t0: span.t0,
+                            t1: t1 - len,
+                            ..f
+                        });
+                    } else {
+                        dst.push(FlatNote { t0: t0, t1: t1, ..f });
+                    }
                 }
                 t
             }
             _ => {
-                return misc::error(&span.path, score.bgn, "syntax error.");
+                return misc::error_range(&span.path, score.bgn, score.end, "syntax error.");
             }
         };
         Ok(end)
@@ -160,12 +473,12 @@ impl<'a> Generator<'a> {
     pub fn generate_score_note(
         &self,
         note: &'a ast::Ast<ast::Note<'a>>,
-        span: &Span<'_>,
+        span: &Span<'a, '_>,
         state: &mut ScoreState<'a>,
         dst: &mut ScoreIr,
     ) -> Result<(), misc::Error> {
         match note.ast {
-            ast::Note::Note(dir, sym, ord, sig) => {
+            ast::Note::Note(dir, ref sym, ord, sig) => {
                 let nnum = match self.get_nnum(note, span, sym, ord)? {
                     Some(v) => v,
                     None => {
@@ -173,6 +486,8 @@ impl<'a> Generator<'a> {
                             t0: span.t0,
                             t1: span.t0 + span.dt,
                             nnum: None,
+                            src: span.locate(note),
+                            marks: span.marks,
                         });
                         return Ok(());
                     }
@@ -186,18 +501,27 @@ impl<'a> Generator<'a> {
                         (_, ast::Dir::Lower, cmp::Ordering::Greater) => -12,
                         _ => 0,
                     };
-                let t0 = match state.prev_ties.iter().position(|e| e.0 == nnum) {
-                    Some(i) => state.prev_ties.remove(i).1,
-                    None => span.t0,
+                let marks = Marks {
+                    dynamic: state.dynamic,
+                    ..span.marks
+                };
+                let (t0, src, marks) = match state.prev_ties.iter().position(|e| e.0 == nnum) {
+                    Some(i) => {
+                        let (_, t0, src, marks) = state.prev_ties.remove(i);
+                        (t0, src, marks)
+                    }
+                    None => (span.t0, span.locate(note), marks),
                 };
                 if span.tied {
-                    state.next_ties.push((nnum, t0));
+                    state.next_ties.push((nnum, t0, src, marks));
                 } else {
                     if span.dt != Ratio::zero() {
                         dst.push(FlatNote {
                             t0: t0,
                             t1: span.t0 + span.dt,
                             nnum: Some(nnum),
+                            src: src,
+                            marks: marks,
                         });
                     }
                 }
@@ -211,6 +535,8 @@ impl<'a> Generator<'a> {
                         t0: span.t0,
                         t1: span.t0 + span.dt,
                         nnum: None,
+                        src: span.locate(note),
+                        marks: span.marks,
                     });
                 }
             }
@@ -219,7 +545,9 @@ impl<'a> Generator<'a> {
                     Some(n) => n,
                     None => match state.note {
                         Some(n) => n,
-                        None => return misc::error(&span.path, note.bgn, "previous note does not exist."),
+                        None => {
+                            return misc::error_range(&span.path, note.bgn, note.end, "previous note does not exist.")
+                        }
                     },
                 };
                 cn.set(Some(rn));
@@ -228,7 +556,36 @@ impl<'a> Generator<'a> {
             ast::Note::Octave(oct) => {
                 state.nnum += oct * 12;
             }
-            ast::Note::OctaveByNote(dir, sym, ord, sig) => {
+            ast::Note::Dynamic(v1) => {
+                // interpolates the dynamics of the notes in the hairpin, including the tied ones.
+                if let Some((t0, i, crescendo, n)) = state.hairpin.take() {
+                    let v0 = state.dynamic.unwrap_or(Ratio::one());
+                    if (v0 < v1) != crescendo || v0 == v1 {
+                        return misc::error_range(&span.path, n.bgn, n.end, "hairpin in the wrong direction.");
+                    }
+                    let t1 = span.t0;
+                    let ramp = |t: Ratio, marks: &mut Marks| {
+                        if t0 <= t && t < t1 {
+                            marks.dynamic = Some(v0 + (v1 - v0) * (t - t0) / (t1 - t0));
+                            marks.hairpin = true;
+                        }
+                    };
+                    for f in dst.iter_mut().skip(i) {
+                        ramp(f.t0, &mut f.marks);
+                    }
+                    for e in state.prev_ties.iter_mut().chain(state.next_ties.iter_mut()) {
+                        ramp(e.1, &mut e.3);
+                    }
+                }
+                state.dynamic = Some(v1);
+            }
+            ast::Note::Hairpin(crescendo) => {
+                if state.hairpin.is_some() {
+                    return misc::error_range(&span.path, note.bgn, note.end, "nested hairpin.");
+                }
+                state.hairpin = Some((span.t0, dst.len(), crescendo, note));
+            }
+            ast::Note::OctaveByNote(dir, ref sym, ord, sig) => {
                 if let Some(v) = self.get_nnum(note, span, sym, ord)? {
                     state.nnum = v + sig;
                     state.dir = dir;
@@ -251,47 +608,84 @@ impl<'a> Generator<'a> {
                 state.note = Some(note);
             }
             ast::Note::Group(ref ns) => {
-                let tot = ns.iter().map(|e| e.1).sum();
-                if tot == 0 {
-                    return misc::error(&span.path, note.end, "zero length group.");
+                let ns: Vec<_> = ns.iter().map(|&(ref n, i)| (&**n, i)).collect();
+                self.generate_score_group(note, &ns, span, state, dst)?;
+            }
+            ast::Note::Ratchet(ref n, cnt) => {
+                if cnt > MAX_DIVISIONS {
+                    return misc::error_range(&span.path, note.bgn, note.end, "too many divisions.");
                 }
-
-                // the most non-trivial part is here...
-                let mut prev_ties = mem::replace(&mut state.prev_ties, Vec::new());
-                let mut next_ties = mem::replace(&mut state.next_ties, Vec::new());
-                let mut acc = 0;
-                for &(ref n, i) in ns.iter() {
-                    let span = Span {
-                        t0: span.t0 + span.dt * Ratio::new(acc, tot),
-                        dt: span.dt * Ratio::new(i, tot),
-                        tied: acc + i == tot && span.tied, // only apply to the last note.
-                        ..*span
-                    };
-                    if acc == 0 {
-                        mem::swap(&mut prev_ties, &mut state.prev_ties);
-                    }
-                    if acc + i == tot {
-                        mem::swap(&mut next_ties, &mut state.next_ties);
-                    }
-                    self.generate_score_note(n, &span, state, dst)?;
-                    if acc == 0 {
-                        mem::swap(&mut prev_ties, &mut state.prev_ties);
-                    }
-                    if acc + i == tot {
-                        mem::swap(&mut next_ties, &mut state.next_ties);
-                    }
-                    if i > 0 {
-                        self.resolve_ties(span.t0, state, dst);
-                    }
-                    acc += i;
+                let ns = vec![(&**n, 1); cnt as usize];
+                self.generate_score_group(note, &ns, span, state, dst)?;
+                state.note = Some(note);
+            }
+            ast::Note::Tremolo(ref n0, ref n1) => {
+                // alternates the notes by the length of the ornaments.
+                if span.ornament <= Ratio::zero() {
+                    return misc::error_range(&span.path, note.bgn, note.end, "non-positive length.");
+                }
+                let cnt = cmp::max((span.dt / span.ornament).floor(), 2);
+                if cnt > MAX_DIVISIONS {
+                    return misc::error_range(&span.path, note.bgn, note.end, "too many divisions.");
                 }
-                state.prev_ties = prev_ties;
-                state.next_ties = next_ties;
+                let ns: Vec<_> = (0..cnt).map(|i| (if i % 2 == 0 { &**n0 } else { &**n1 }, 1)).collect();
+                self.generate_score_group(note, &ns, span, state, dst)?;
+                state.note = Some(note);
+            }
+            ast::Note::Articulation(ref n, kind) => {
+                let mut marks = span.marks;
+                match kind {
+                    ast::Articulation::Accent => marks.accent = true,
+                    ast::Articulation::Staccato => marks.staccato = true,
+                    ast::Articulation::Tenuto => marks.tenuto = true,
+                }
+                let span = Span { marks: marks, ..*span };
+                self.generate_score_note(n, &span, state, dst)?;
+                state.note = Some(note);
             }
             ast::Note::Tie(ref n) => {
                 let span = Span { tied: true, ..*span };
                 self.generate_score_note(n, &span, state, dst)?
             }
+            ast::Note::Grace(ref grace, ref main, before) => {
+                let n = match grace.ast {
+                    ast::Note::Group(ref ns) => ns.iter().map(|e| e.1).sum(),
+                    _ => 1,
+                };
+                // an appoggiatura takes at most the half of the main note.
+                let len = if before {
+                    span.ornament * n
+                } else {
+                    cmp::min(span.ornament * n, span.dt / 2)
+                };
+                let span_grace = Span {
+                    t0: if before { span.t0 - len } else { span.t0 },
+                    dt: len,
+                    tied: false,
+                    ..*span
+                };
+                self.generate_score_note(grace, &span_grace, state, dst)?;
+                if before {
+                    self.generate_score_note(main, span, state, dst)?;
+                } else {
+                    let span = Span {
+                        t0: span.t0 + len,
+                        dt: span.dt - len,
+                        ..*span
+                    };
+                    self.generate_score_note(main, &span, state, dst)?;
+                }
+                state.note = Some(note);
+            }
+            ast::Note::Ornament(ref main, kind) => {
+                let marks = Marks {
+                    ornament: Some(kind),
+                    ..span.marks
+                };
+                let span = Span { marks: marks, ..*span };
+                self.generate_score_note(main, &span, state, dst)?;
+                state.note = Some(note);
+            }
             // XXX
             ast::Note::ChordSymbol(ref text) => {
                 use crate::chord;
@@ -301,18 +695,27 @@ impl<'a> Generator<'a> {
                 for n in chord.iter() {
                     let nnum = *n as i64;
                     // XXX
-                    let t0 = match state.prev_ties.iter().position(|e| e.0 == nnum) {
-                        Some(i) => state.prev_ties.remove(i).1,
-                        None => span.t0,
+                    let marks = Marks {
+                        dynamic: state.dynamic,
+                        ..span.marks
+                    };
+                    let (t0, src, marks) = match state.prev_ties.iter().position(|e| e.0 == nnum) {
+                        Some(i) => {
+                            let (_, t0, src, marks) = state.prev_ties.remove(i);
+                            (t0, src, marks)
+                        }
+                        None => (span.t0, span.locate(note), marks),
                     };
                     if span.tied {
-                        state.next_ties.push((nnum, t0));
+                        state.next_ties.push((nnum, t0, src, marks));
                     } else {
                         if span.dt != Ratio::zero() {
                             dst.push(FlatNote {
                                 t0: t0,
                                 t1: span.t0 + span.dt,
                                 nnum: Some(nnum),
+                                src: src,
+                                marks: marks,
                             });
                         }
                     }
@@ -320,44 +723,198 @@ impl<'a> Generator<'a> {
                 state.note = Some(note);
             }
             _ => {
-                return misc::error(&span.path, note.bgn, "syntax error.");
+                return misc::error_range(&span.path, note.bgn, note.end, "syntax error.");
+            }
+        }
+        Ok(())
+    }
+
+    // divides the span by the weights of the notes.
+    fn generate_score_group(
+        &self,
+        note: &'a ast::Ast<ast::Note<'a>>,
+        ns: &[(&'a ast::Ast<ast::Note<'a>>, i64)],
+        span: &Span<'a, '_>,
+        state: &mut ScoreState<'a>,
+        dst: &mut ScoreIr,
+    ) -> Result<(), misc::Error> {
+        let tot = ns.iter().map(|e| e.1).sum();
+        if tot == 0 {
+            return misc::error(&span.path, note.end, "zero length group.");
+        }
+
+        // the most non-trivial part is here...
+        let mut prev_ties = mem::replace(&mut state.prev_ties, Vec::new());
+        let mut next_ties = mem::replace(&mut state.next_ties, Vec::new());
+        let mut acc = 0;
+        for &(n, i) in ns.iter() {
+            let span = Span {
+                t0: span.t0 + span.dt * Ratio::new(acc, tot),
+                dt: span.dt * Ratio::new(i, tot),
+                tied: acc + i == tot && span.tied, // only apply to the last note.
+                ..*span
+            };
+            if acc == 0 {
+                mem::swap(&mut prev_ties, &mut state.prev_ties);
+            }
+            if acc + i == tot {
+                mem::swap(&mut next_ties, &mut state.next_ties);
+            }
+            self.generate_score_note(n, &span, state, dst)?;
+            if acc == 0 {
+                mem::swap(&mut prev_ties, &mut state.prev_ties);
+            }
+            if acc + i == tot {
+                mem::swap(&mut next_ties, &mut state.next_ties);
             }
+            if i > 0 {
+                self.resolve_ties(span.t0, state, dst);
+            }
+            acc += i;
         }
+        state.prev_ties = prev_ties;
+        state.next_ties = next_ties;
         Ok(())
     }
 
     fn get_nnum(
         &self,
         note: &'a ast::Ast<ast::Note<'a>>,
-        span: &Span<'_>,
-        sym: char,
+        span: &Span<'a, '_>,
+        sym: &str,
         ord: i64,
     ) -> Result<Option<i64>, misc::Error> {
-        let fs = match span.syms.get(&sym) {
+        let fs = match span.syms.get(sym) {
             Some(v) => v,
-            None => return misc::error(&span.path, note.bgn, "note does not exist."),
+            None => return misc::error_range(&span.path, note.bgn, note.end, "note does not exist."),
         };
         // XXX: O(N^2).
-        let f = match fs
+        let mut it = fs.iter().filter(|n| n.t0 <= span.t0 && span.t0 < n.t1);
+        let nnum = if ord >= 0 {
+            it.nth(ord as usize).map(|f| f.nnum)
+        } else {
+            // negative ordinals count from the highest sounding note.
+            let mut nnums: Vec<_> = it.filter_map(|f| f.nnum).collect();
+            nnums.sort_by(|x, y| y.cmp(x));
+            nnums.get((-ord - 1) as usize).map(|&v| Some(v))
+        };
+        match nnum {
+            Some(v) => Ok(v),
+            None => misc::error_range(&span.path, note.bgn, note.end, "note does not exist."),
+        }
+    }
+
+    // distinguishes the notes from the values in "{...}".
+    fn has_pitch(note: &ast::Ast<ast::Note<'a>>) -> bool {
+        match note.ast {
+            ast::Note::Note(..) | ast::Note::ChordSymbol(_) => true,
+            ast::Note::Chord(ref ns) | ast::Note::Group(ref ns) => ns.iter().any(|(n, _)| Self::has_pitch(n)),
+            ast::Note::Tie(ref n)
+            | ast::Note::Grace(_, ref n, _)
+            | ast::Note::Ornament(ref n, _)
+            | ast::Note::Ratchet(ref n, _)
+            | ast::Note::Tremolo(ref n, _)
+            | ast::Note::Articulation(ref n, _) => Self::has_pitch(n),
+            _ => false,
+        }
+    }
+
+    // "$scale.major(root)" etc. build the scales on the roots.
+    pub fn scale_intervals(key: &str, args: &[Box<ast::Ast<ast::Score<'a>>>]) -> Option<&'static [i64]> {
+        match (key.strip_prefix("scale."), args.len()) {
+            (Some(name), 1) => scale::intervals(name),
+            _ => None,
+        }
+    }
+
+    // moves "nnum" by "n" degrees of the scale sounding at "t".  a note out of the scale keeps the
+    // offset from the degree below.
+    fn transpose_by_degree(scale: &[FlatNote], t: Ratio, nnum: i64, n: i64) -> Option<i64> {
+        let mut pcs: Vec<_> = scale
             .iter()
-            .filter(|n| n.t0 <= span.t0 && span.t0 < n.t1)
-            .nth(ord as usize)
-        {
-            Some(v) => v,
-            None => return misc::error(&span.path, note.bgn, "note does not exist."),
+            .filter(|f| f.t0 <= t && t < f.t1)
+            .filter_map(|f| f.nnum.map(|v| misc::imod(v, 12)))
+            .collect();
+        pcs.sort();
+        pcs.dedup();
+        if pcs.is_empty() {
+            return None;
+        }
+
+        let m = pcs.len() as i64;
+        let pc = misc::imod(nnum, 12);
+        let i = pcs.iter().rposition(|&v| v <= pc).map(|i| i as i64).unwrap_or(-1);
+        let base = misc::idiv(i, m) * 12 + pcs[misc::imod(i, m) as usize];
+        let j = i + n;
+        let dest = misc::idiv(j, m) * 12 + pcs[misc::imod(j, m) as usize];
+        Some(nnum - base + dest)
+    }
+
+    fn expand_ornament(
+        &self,
+        f: FlatNote,
+        score: &'a ast::Ast<ast::Score<'a>>,
+        span: &Span<'a, '_>,
+        dst: &mut ScoreIr,
+    ) -> Result<(), misc::Error> {
+        let (nnum, kind) = match (f.nnum, f.marks.ornament) {
+            (Some(nnum), Some(kind)) => (nnum, kind),
+            _ => {
+                dst.push(f);
+                return Ok(());
+            }
+        };
+        let step = match kind {
+            ast::Ornament::Trill | ast::Ornament::UpperMordent => 1,
+            ast::Ornament::LowerMordent => -1,
+        };
+        // the neighbor in the current scale, or a whole tone if it is not available.
+        let other = match span.syms.get("*") {
+            Some(scale) => Self::transpose_by_degree(scale, f.t0, nnum, step),
+            None => None,
+        };
+        let other = other.unwrap_or(nnum + 2 * step);
+        let pattern = match kind {
+            ast::Ornament::Trill => {
+                let n = cmp::max(((f.t1 - f.t0) / span.ornament).floor(), 1);
+                if n > MAX_DIVISIONS {
+                    let (bgn, end) = f.src.as_ref().map_or((score.bgn, score.end), |e| (e.bgn, e.end));
+                    return misc::error_range(&span.path, bgn, end, "too many divisions.");
+                }
+                (0..n).map(|i| if i % 2 == 0 { nnum } else { other }).collect()
+            }
+            ast::Ornament::UpperMordent | ast::Ornament::LowerMordent => vec![nnum, other, nnum],
+        };
+        // the last note takes the rest of the main note.
+        let len = cmp::min(span.ornament, (f.t1 - f.t0) / pattern.len() as i64);
+        let marks = Marks {
+            ornament: None,
+            ..f.marks
         };
-        Ok(f.nnum)
+        for (i, &n) in pattern.iter().enumerate() {
+            let t0 = f.t0 + len * i as i64;
+            let t1 = if i + 1 == pattern.len() { f.t1 } else { t0 + len };
+            dst.push(FlatNote {
+                t0: t0,
+                t1: t1,
+                nnum: Some(n),
+                marks: marks,
+                ..f.clone()
+            });
+        }
+        Ok(())
     }
 
     fn resolve_ties(&self, t1: Ratio, state: &mut ScoreState<'_>, dst: &mut ScoreIr) {
-        for &(nnum, t0) in state.prev_ties.iter() {
+        for (nnum, t0, src, marks) in state.prev_ties.drain(..) {
             dst.push(FlatNote {
                 t0: t0,
                 t1: t1,
                 nnum: Some(nnum),
+                src: src,
+                marks: marks,
             });
         }
-        state.prev_ties.clear();
         mem::swap(&mut state.prev_ties, &mut state.next_ties);
     }
 }
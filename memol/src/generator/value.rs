@@ -8,44 +8,107 @@ use std::*;
 #[derive(Debug)]
 pub enum ValueIr {
     Value(Ratio, Ratio, Ratio, Ratio),
+    Curve(Ratio, Ratio, Ratio, Ratio, ast::Curve),
     Sequence(Ratio, Vec<(ValueIr, Ratio)>),
     BinaryOp(Box<ValueIr>, Box<ValueIr>, ast::BinaryOp),
     Branch(Box<ValueIr>, Box<ValueIr>, Box<ValueIr>),
+    // a built-in function applied to the arguments.
+    Call(Function, Vec<ValueIr>),
     Time,
+    // the position in the bar and the index of the bar.
+    Beat,
+    Bar,
     Gauss,
+    Rand,
     NoteLen,
     NoteCnt,
     NoteNth,
+    NotePitch,
+    NoteTime,
+    NotePrevPitch,
+    NoteNextPitch,
+    NoteTop,
+    NoteBottom,
+    NoteVelocity,
 }
 
+// the LFOs take the rate in cycles per unit time and the optional phase in cycles.
+#[derive(Copy, Clone, Debug)]
+pub enum Function {
+    Sin,
+    Tri,
+    Saw,
+    Square,
+    Min,
+    Max,
+    Abs,
+    Floor,
+    Clamp,
+    Pow,
+    Exp,
+    Mod,
+}
+
+// the built-in functions and their numbers of arguments.
+const FUNCTIONS: &[(&str, usize, usize, Function)] = &[
+    ("sin", 1, 2, Function::Sin),
+    ("tri", 1, 2, Function::Tri),
+    ("saw", 1, 2, Function::Saw),
+    ("square", 1, 2, Function::Square),
+    ("min", 2, 2, Function::Min),
+    ("max", 2, 2, Function::Max),
+    ("abs", 1, 1, Function::Abs),
+    ("floor", 1, 1, Function::Floor),
+    ("clamp", 3, 3, Function::Clamp),
+    ("pow", 2, 2, Function::Pow),
+    ("exp", 1, 1, Function::Exp),
+    ("mod", 2, 2, Function::Mod),
+];
+
 pub struct ValueState<'a> {
     t: Ratio,
     v: Ratio,
+    // the curve to the next value, given by "..{curve}*".
+    curve: ast::Curve,
     note: Option<&'a ast::Ast<ast::Note<'a>>>,
 }
 
 impl<'a> Generator<'a> {
-    pub fn generate_value(&self, key: &str) -> Result<Option<ValueIr>, misc::Error> {
-        let syms = self.syms.iter().map(|&(s, ref ns)| (s, &ns[..])).collect();
-        let &(ref path, ref s) = match self.defs.values.get(key) {
-            Some(v) => v,
-            None => return Ok(None),
-        };
+    // returns None if the value is not defined or fails to be generated.  errors are reported to
+    // the diagnostics.
+    pub fn generate_value(&self, key: &str) -> Option<ValueIr> {
+        let syms = self.syms.iter().map(|&(ref s, ref ns)| (s.as_str(), &ns[..])).collect();
+        let f = self.defs.values.get(key)?;
+        if f.params.len() != 0 {
+            self.report(misc::Error::range(&f.path, f.bgn, f.end, "wrong number of arguments."));
+            return None;
+        }
+        let scope = Scope::new();
         let span = Span {
             t0: Ratio::zero(),
             dt: Ratio::one(),
             tied: false,
+            repeat: None,
+            ornament: Ratio::new(1, 32),
+            marks: Marks::default(),
             syms: &syms,
-            path: path,
+            path: &f.path,
+            ns: &f.ns,
+            scope: &scope,
         };
-        let (ir, _) = self.generate_value_inner(s, &span)?;
-        Ok(Some(ir))
+        match self.generate_value_inner(&f.body, &span) {
+            Ok((ir, _)) => Some(ir),
+            Err(e) => {
+                self.report(e);
+                None
+            }
+        }
     }
 
     pub fn generate_value_inner(
         &self,
         track: &'a ast::Ast<ast::Score<'a>>,
-        span: &Span<'_>,
+        span: &Span<'a, '_>,
     ) -> Result<(ValueIr, Ratio), misc::Error> {
         let dst = match track.ast {
             ast::Score::Score(ref ns) => {
@@ -53,6 +116,7 @@ impl<'a> Generator<'a> {
                 let mut state = ValueState {
                     t: span.t0,
                     v: Ratio::zero(),
+                    curve: ast::Curve::Linear,
                     note: None,
                 };
                 for (i, v) in ns.iter().enumerate() {
@@ -68,24 +132,59 @@ impl<'a> Generator<'a> {
                 }
                 (ValueIr::Sequence(span.t0, irs), t1)
             }
-            ast::Score::Symbol(ref key) => match key.as_str() {
-                "time" => (ValueIr::Time, span.t0),
-                "gauss" => (ValueIr::Gauss, span.t0),
-                "note.len" => (ValueIr::NoteLen, span.t0),
-                "note.cnt" => (ValueIr::NoteCnt, span.t0),
-                "note.nth" => (ValueIr::NoteNth, span.t0),
-                _ => {
-                    let &(ref path, ref s) = match self.defs.values.get(key) {
-                        Some(v) => v,
-                        None => return misc::error(&span.path, track.bgn, "undefined symbol."),
-                    };
-                    let span = Span { path: path, ..*span };
-                    self.generate_value_inner(s, &span)?
+            ast::Score::Symbol(ref key, ref args) => {
+                // the definitions precede the built-in symbols.
+                let v = self.call(track, &self.defs.values, span, |s, span| {
+                    self.generate_value_inner(s, span)
+                })?;
+                match (v, key.as_str(), args.len()) {
+                    (Some(v), _, _) => v,
+                    (None, "time", 0) => (ValueIr::Time, span.t0),
+                    (None, "gauss", 0) => (ValueIr::Gauss, span.t0),
+                    (None, "rand", 0) => (ValueIr::Rand, span.t0),
+                    (None, "note.len", 0) => (ValueIr::NoteLen, span.t0),
+                    (None, "note.cnt", 0) => (ValueIr::NoteCnt, span.t0),
+                    (None, "note.nth", 0) => (ValueIr::NoteNth, span.t0),
+                    (None, "note.pitch", 0) => (ValueIr::NotePitch, span.t0),
+                    (None, "note.time", 0) => (ValueIr::NoteTime, span.t0),
+                    (None, "note.prev_pitch", 0) => (ValueIr::NotePrevPitch, span.t0),
+                    (None, "note.next_pitch", 0) => (ValueIr::NoteNextPitch, span.t0),
+                    (None, "note.top", 0) => (ValueIr::NoteTop, span.t0),
+                    (None, "note.bottom", 0) => (ValueIr::NoteBottom, span.t0),
+                    (None, "note.velocity", 0) => (ValueIr::NoteVelocity, span.t0),
+                    (None, "beat", 0) => (ValueIr::Beat, span.t0),
+                    (None, "bar", 0) => (ValueIr::Bar, span.t0),
+                    (None, "repeat.i", 0) => (Self::constant_value(span.repeat.map_or(0, |r| r.0).into()), span.t0),
+                    (None, "repeat.n", 0) => (Self::constant_value(span.repeat.map_or(1, |r| r.1).into()), span.t0),
+                    (None, "dynamic", 1) => {
+                        let mut notes = Vec::new();
+                        let t = self.generate_score_inner(&args[0], span, &mut notes)?;
+                        (Self::dynamic_value(&notes), t)
+                    }
+                    (None, key, n) if Self::function(key, n).is_some() => {
+                        let mut irs = Vec::new();
+                        for arg in args.iter() {
+                            irs.push(self.generate_value_inner(arg, span)?.0);
+                        }
+                        (ValueIr::Call(Self::function(key, n).unwrap(), irs), span.t0)
+                    }
+                    (None, _, _) => {
+                        self.report(misc::Error::range(
+                            &span.path,
+                            track.bgn,
+                            track.end,
+                            "undefined symbol.",
+                        ));
+                        (Self::error_value(), span.t0)
+                    }
                 }
-            },
+            }
+            ast::Score::Let(..) => self.bind(track, span, |s, span| self.generate_value_inner(s, span))?,
+            // already reported by the parser.
+            ast::Score::Error => (Self::error_value(), span.t0),
             ast::Score::Parallel(ref ss) => {
                 if ss.len() != 1 {
-                    return misc::error(&span.path, track.bgn, "syntax error.");
+                    return misc::error_range(&span.path, track.bgn, track.end, "syntax error.");
                 }
                 self.generate_value_inner(&ss[0], &span)?
             }
@@ -103,15 +202,20 @@ impl<'a> Generator<'a> {
             ast::Score::Repeat(ref s, n) => {
                 let mut irs = Vec::new();
                 let mut t = span.t0;
-                for _ in 0..n {
-                    let span = Span { t0: t, ..*span };
+                for i in 0..n {
+                    let span = Span {
+                        t0: t,
+                        repeat: Some((i, n)),
+                        ..*span
+                    };
                     let (ir, t1) = self.generate_value_inner(s, &span)?;
                     irs.push((ir, t1));
                     t = t1;
                 }
                 (ValueIr::Sequence(span.t0, irs), t)
             }
-            ast::Score::Stretch(ref s, r) => {
+            ast::Score::Stretch(ref s, ref r) => {
+                let r = self.generate_ratio(r, &span)?;
                 let span = Span {
                     dt: r * span.dt,
                     ..*span
@@ -133,7 +237,17 @@ impl<'a> Generator<'a> {
                 let t = cmp::max(t_then, t_elze);
                 (ir, t)
             }
-            ast::Score::Slice(ref s, t0, t1) => {
+            ast::Score::Choose(ref ss) => {
+                let i = self.rng.next_u64() % ss.len() as u64;
+                self.generate_value_inner(&ss[i as usize], &span)?
+            }
+            ast::Score::Volta(ref ss) => match span.volta(ss) {
+                Some(s) => self.generate_value_inner(s, &span)?,
+                None => return misc::error_range(&span.path, track.bgn, track.end, "volta outside repeat."),
+            },
+            ast::Score::Slice(ref s, ref t0, ref t1) => {
+                let t0 = self.generate_ratio(t0, &span)?;
+                let t1 = self.generate_ratio(t1, &span)?;
                 let span1 = Span {
                     t0: span.t0 - t0,
                     ..*span
@@ -143,34 +257,193 @@ impl<'a> Generator<'a> {
                 (ValueIr::Sequence(span.t0, vec![(ir, t)]), t)
             }
             _ => {
-                return misc::error(&span.path, track.bgn, "syntax error.");
+                return misc::error_range(&span.path, track.bgn, track.end, "syntax error.");
+            }
+        };
+        Ok(dst)
+    }
+
+    // evaluates a constant expression exactly.
+    pub fn generate_ratio(
+        &self,
+        track: &'a ast::Ast<ast::Score<'a>>,
+        span: &Span<'a, '_>,
+    ) -> Result<Ratio, misc::Error> {
+        let dst = match track.ast {
+            ast::Score::Score(ref ns) if ns.len() == 1 => match ns[0].ast {
+                ast::Note::Value(Some(v0), Some(v1), _) if v0 == v1 => v0,
+                _ => return misc::error_range(&span.path, track.bgn, track.end, "constant expected."),
+            },
+            ast::Score::Symbol(ref key, ref args) => {
+                let v = self.call(track, &self.defs.values, span, |s, span| self.generate_ratio(s, span))?;
+                match (v, key.as_str(), args.len()) {
+                    (Some(v), _, _) => v,
+                    (None, "repeat.i", 0) => span.repeat.map_or(0, |r| r.0).into(),
+                    (None, "repeat.n", 0) => span.repeat.map_or(1, |r| r.1).into(),
+                    (None, key, _) if Self::builtin(key, args) => {
+                        return misc::error_range(&span.path, track.bgn, track.end, "constant expected.");
+                    }
+                    (None, _, _) => {
+                        self.report(misc::Error::range(
+                            &span.path,
+                            track.bgn,
+                            track.end,
+                            "undefined symbol.",
+                        ));
+                        Ratio::one()
+                    }
+                }
+            }
+            ast::Score::Let(..) => self.bind(track, span, |s, span| self.generate_ratio(s, span))?,
+            ast::Score::Error => Ratio::one(),
+            ast::Score::Parallel(ref ss) if ss.len() == 1 => self.generate_ratio(&ss[0], span)?,
+            ast::Score::BinaryOp(ref lhs, ref rhs, op) => {
+                let lhs = self.generate_ratio(lhs, span)?;
+                let rhs = self.generate_ratio(rhs, span)?;
+                match op {
+                    ast::BinaryOp::Add => lhs + rhs,
+                    ast::BinaryOp::Sub => lhs - rhs,
+                    ast::BinaryOp::Mul => lhs * rhs,
+                    ast::BinaryOp::Div => {
+                        if rhs == Ratio::zero() {
+                            return misc::error_range(&span.path, track.bgn, track.end, "division by zero.");
+                        }
+                        lhs / rhs
+                    }
+                    _ => return misc::error_range(&span.path, track.bgn, track.end, "constant expected."),
+                }
             }
+            _ => return misc::error_range(&span.path, track.bgn, track.end, "constant expected."),
         };
         Ok(dst)
     }
 
+    // the built-in symbols of scores and values.
+    pub fn builtin(key: &str, args: &[Box<ast::Ast<ast::Score<'a>>>]) -> bool {
+        const SYMBOLS: &[(&str, usize)] = &[
+            ("time", 0),
+            ("gauss", 0),
+            ("rand", 0),
+            ("note.len", 0),
+            ("note.cnt", 0),
+            ("note.nth", 0),
+            ("note.pitch", 0),
+            ("note.time", 0),
+            ("note.prev_pitch", 0),
+            ("note.next_pitch", 0),
+            ("note.top", 0),
+            ("note.bottom", 0),
+            ("note.velocity", 0),
+            ("beat", 0),
+            ("bar", 0),
+            ("repeat.i", 0),
+            ("repeat.n", 0),
+            ("dynamic", 1),
+        ];
+        SYMBOLS.contains(&(key, args.len()))
+            || Self::function(key, args.len()).is_some()
+            || Self::scale_intervals(key, args).is_some()
+    }
+
+    fn function(key: &str, n: usize) -> Option<Function> {
+        FUNCTIONS
+            .iter()
+            .find(|&&(k, n0, n1, _)| k == key && n0 <= n && n <= n1)
+            .map(|&(_, _, _, f)| f)
+    }
+
+    fn constant_value(v: Ratio) -> ValueIr {
+        ValueIr::Value(Ratio::zero(), Ratio::one(), v, v)
+    }
+
+    // the dynamics of the notes relative to "mf", which change linearly in the hairpins.
+    fn dynamic_value(notes: &[FlatNote]) -> ValueIr {
+        let mut notes: Vec<_> = notes.iter().filter(|f| f.nnum.is_some()).collect();
+        notes.sort_by_key(|f| f.t0);
+        notes.dedup_by_key(|f| f.t0);
+        let level = |f: &FlatNote| f.marks.dynamic.unwrap_or(Ratio::one());
+        let mut irs = Vec::new();
+        for (i, f) in notes.iter().enumerate() {
+            let (t1, v1) = match notes.get(i + 1) {
+                Some(g) if f.marks.hairpin => (g.t0, level(g)),
+                Some(g) => (g.t0, level(f)),
+                None => (f.t1, level(f)),
+            };
+            irs.push((ValueIr::Value(f.t0, t1, level(f), v1), t1));
+        }
+        match notes.first() {
+            Some(f) => ValueIr::Sequence(f.t0, irs),
+            None => Self::constant_value(Ratio::one()),
+        }
+    }
+
+    // the pitches of the notes, each of which holds until the next one.  the lowest one is taken
+    // from a chord.
+    pub fn pitch_value(notes: &[FlatNote]) -> Option<ValueIr> {
+        let mut notes: Vec<_> = notes.iter().filter(|f| f.nnum.is_some()).collect();
+        notes.sort_by_key(|f| (f.t0, f.nnum));
+        notes.dedup_by_key(|f| f.t0);
+        let mut irs = Vec::new();
+        for (i, f) in notes.iter().enumerate() {
+            let t1 = notes.get(i + 1).map_or(f.t1, |g| g.t0);
+            let v = f.nnum.unwrap().into();
+            irs.push((ValueIr::Value(f.t0, t1, v, v), t1));
+        }
+        notes.first().map(|f| ValueIr::Sequence(f.t0, irs))
+    }
+
+    // an exponential curve is defined only between the values of the same sign.
+    fn curve_value(
+        note: &'a ast::Ast<ast::Note<'a>>,
+        span: &Span<'a, '_>,
+        t0: Ratio,
+        t1: Ratio,
+        v0: Ratio,
+        v1: Ratio,
+        curve: ast::Curve,
+    ) -> Result<ValueIr, misc::Error> {
+        match curve {
+            ast::Curve::Linear => Ok(ValueIr::Value(t0, t1, v0, v1)),
+            ast::Curve::Exponential | ast::Curve::Logarithmic if v0 * v1 <= Ratio::zero() => {
+                misc::error_range(&span.path, note.bgn, note.end, "exponential curve across zero.")
+            }
+            _ => Ok(ValueIr::Curve(t0, t1, v0, v1, curve)),
+        }
+    }
+
+    // a placeholder for the erroneous part, with which the generation continues.
+    fn error_value() -> ValueIr {
+        Self::constant_value(Ratio::zero())
+    }
+
     pub fn generate_value_note(
         &self,
         note: &'a ast::Ast<ast::Note<'a>>,
-        span: &Span<'_>,
+        span: &Span<'a, '_>,
         state: &mut ValueState<'a>,
         dst: &mut Vec<(ValueIr, Ratio)>,
     ) -> Result<(), misc::Error> {
         match note.ast {
-            ast::Note::Value(v0, v1) => {
+            ast::Note::Value(v0, v1, curve) => {
                 if let Some(v0) = v0 {
                     if state.t != span.t0 {
-                        dst.push((ValueIr::Value(state.t, span.t0, state.v, v0), span.t0));
+                        let ir = Self::curve_value(note, span, state.t, span.t0, state.v, v0, state.curve)?;
+                        dst.push((ir, span.t0));
                     }
                     state.t = span.t0;
                     state.v = v0;
                 }
                 if let Some(v1) = v1 {
                     let t1 = span.t0 + span.dt;
-                    dst.push((ValueIr::Value(state.t, t1, state.v, v1), t1));
+                    dst.push((Self::curve_value(note, span, state.t, t1, state.v, v1, curve)?, t1));
                     state.t = t1;
                     state.v = v1;
                 }
+                state.curve = match (v0, v1) {
+                    (_, Some(_)) => ast::Curve::Linear,
+                    (Some(_), None) => curve,
+                    (None, None) => state.curve,
+                };
                 state.note = Some(note);
             }
             ast::Note::Repeat(ref cn) => {
@@ -178,7 +451,9 @@ impl<'a> Generator<'a> {
                     Some(n) => n,
                     None => match state.note {
                         Some(n) => n,
-                        None => return misc::error(&span.path, note.bgn, "previous note does not exist."),
+                        None => {
+                            return misc::error_range(&span.path, note.bgn, note.end, "previous note does not exist.")
+                        }
                     },
                 };
                 cn.set(Some(rn));
@@ -188,7 +463,7 @@ impl<'a> Generator<'a> {
                 let mut acc = 0;
                 for &(ref n, i) in ns.iter() {
                     if acc > 0 {
-                        return misc::error(&span.path, note.bgn, "syntax error.");
+                        return misc::error_range(&span.path, note.bgn, note.end, "syntax error.");
                     }
                     self.generate_value_note(n, span, state, dst)?;
                     acc += i;
@@ -209,7 +484,7 @@ impl<'a> Generator<'a> {
                 }
             }
             _ => {
-                return misc::error(&span.path, note.bgn, "syntax error.");
+                return misc::error_range(&span.path, note.bgn, note.end, "syntax error.");
             }
         }
         Ok(())
@@ -0,0 +1,325 @@
+// (c) Yasuhiro Fujii <http://mimosa-pudica.net>, under MIT License.
+use crate::misc;
+use std::*;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Tok<'a> {
+    // reserved words and punctuations.
+    Keyword(&'a str),
+    PitchLower(char),
+    PitchUpper(char),
+    SymbolLower(char),
+    SymbolUpper(char),
+    Int(&'a str),
+    // decimals, and signed numbers and fractions in parentheses in "{...}".
+    Number(&'a str),
+    Label(&'a str),
+    Str(&'a str),
+    ChordSymbol(&'a str),
+    // named symbols in "{...}", e.g. "{bass}" and "{Bass}".
+    SymbolNameLower(&'a str),
+    SymbolNameUpper(&'a str),
+    // dynamics and hairpins in "{...}", e.g. "\pp" and "\<".
+    Dynamic(&'a str),
+}
+
+#[derive(Clone, Debug)]
+pub struct Token<'a> {
+    pub bgn: usize,
+    pub end: usize,
+    pub tok: Tok<'a>,
+    // the ranges of the comments between the previous token and this.
+    pub comments: Vec<(usize, usize)>,
+}
+
+const KEYWORDS: &[&str] = &[
+    "arpeggio",
+    "as",
+    "choose",
+    "down",
+    "dtranspose",
+    "else",
+    "filter",
+    "fit",
+    "if",
+    "import",
+    "in",
+    "invert",
+    "key",
+    "let",
+    "ornament",
+    "random",
+    "repeat",
+    "reverse",
+    "rotate",
+    "score",
+    "shift",
+    "slice",
+    "stretch",
+    "then",
+    "transpose",
+    "up",
+    "updown",
+    "value",
+    "volta",
+    "with",
+];
+
+// longer ones first.
+const PUNCTUATIONS: &[&str] = &[
+    "..", "||", "&&", "==", "!=", "<=", ">=", "''", "%%", "(", ")", "[", "]", "{", "}", "=", ",", "|", ":", "^", "/",
+    "*", "+", "-", "_", "<", ">", "@", "!", "'", "~", "%", ".",
+];
+
+// the curves taking a parameter in parentheses, e.g. "0..{bezier}(1/2)1".
+const PARAMETRIC_CURVES: &[&str] = &["bezier"];
+
+pub struct Lexer<'a> {
+    path: &'a path::Path,
+    src: &'a str,
+    pos: usize,
+    // keywords are not recognized in "{...}", where every letter is a note.
+    depth: usize,
+    // whether the previous token is a curve taking a parameter, which "(...)" is lexed as.
+    curve: bool,
+    // the comments not attached to any token yet.  after the iteration, it holds the trailing
+    // comments.
+    pub comments: Vec<(usize, usize)>,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(path: &'a path::Path, src: &'a str) -> Self {
+        Lexer {
+            path: path,
+            src: src,
+            pos: 0,
+            depth: 0,
+            curve: false,
+            comments: Vec::new(),
+        }
+    }
+
+    fn skip_while<F: Fn(char) -> bool>(&mut self, f: F) {
+        let rest = &self.src[self.pos..];
+        self.pos += rest.find(|c| !f(c)).unwrap_or(rest.len());
+    }
+
+    fn skip_trivia(&mut self) -> Result<(), misc::Error> {
+        loop {
+            self.skip_while(char::is_whitespace);
+            let bgn = self.pos;
+            let rest = &self.src[self.pos..];
+            if rest.starts_with("//") {
+                self.skip_while(|c| c != '\n');
+            } else if rest.starts_with("/*") {
+                let mut depth = 0;
+                loop {
+                    let rest = &self.src[self.pos..];
+                    if rest.starts_with("/*") {
+                        depth += 1;
+                        self.pos += 2;
+                    } else if rest.starts_with("*/") {
+                        depth -= 1;
+                        self.pos += 2;
+                        if depth == 0 {
+                            break;
+                        }
+                    } else if let Some(c) = rest.chars().next() {
+                        self.pos += c.len_utf8();
+                    } else {
+                        return misc::error(self.path, bgn, "unterminated comment.");
+                    }
+                }
+            } else {
+                return Ok(());
+            }
+            self.comments.push((bgn, self.pos));
+        }
+    }
+
+    // returns the length of "-?[0-9]+([./][0-9]+)?" at the beginning of the text.
+    fn number_len(text: &str) -> usize {
+        let digits = |i: usize| text[i..].find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len() - i);
+        let sign = if text.starts_with('-') { 1 } else { 0 };
+        let n = sign + digits(sign);
+        if n == sign {
+            return 0;
+        }
+        match text[n..].chars().next() {
+            Some('.') | Some('/') if digits(n + 1) > 0 => n + 1 + digits(n + 1),
+            _ => n,
+        }
+    }
+
+    fn next_tok(&mut self) -> Result<Tok<'a>, misc::Error> {
+        let bgn = self.pos;
+        let c = self.src[self.pos..].chars().next().unwrap();
+
+        if c.is_ascii_alphabetic() {
+            let rest = &self.src[self.pos..];
+            let n = rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len());
+            if self.depth == 0 && n > 1 {
+                return match KEYWORDS.iter().find(|&&k| k == &rest[..n]) {
+                    Some(_) => {
+                        self.pos += n;
+                        Ok(Tok::Keyword(&rest[..n]))
+                    }
+                    None => misc::error(self.path, bgn, "unexpected token."),
+                };
+            }
+            self.pos += 1;
+            return Ok(match c {
+                'a'..='g' => Tok::PitchLower(c),
+                'A'..='G' => Tok::PitchUpper(c),
+                'h'..='z' => Tok::SymbolLower(c),
+                _ => Tok::SymbolUpper(c),
+            });
+        }
+
+        if c.is_ascii_digit() {
+            // a fraction is lexed as integers, since "/" is a division outside "{...}" and a repeat inside.
+            let rest = &self.src[self.pos..];
+            let n = Self::number_len(rest);
+            if rest[..n].contains('/') {
+                self.skip_while(|c| c.is_ascii_digit());
+                return Ok(Tok::Int(&self.src[bgn..self.pos]));
+            }
+            self.pos += n;
+            return Ok(match rest[..n].contains('.') {
+                true => Tok::Number(&rest[..n]),
+                false => Tok::Int(&rest[..n]),
+            });
+        }
+
+        match c {
+            '$' => {
+                self.pos += 1;
+                self.skip_while(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+                if self.pos == bgn + 1 {
+                    return misc::error(self.path, bgn, "unexpected token.");
+                }
+                return Ok(Tok::Label(&self.src[bgn..self.pos]));
+            }
+            '{' if self.depth > 0 => {
+                let rest = &self.src[bgn + 1..];
+                let n = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.')
+                    .unwrap_or(rest.len());
+                if n > 0 && rest[n..].starts_with('}') {
+                    match rest.as_bytes()[0] {
+                        b'a'..=b'z' => {
+                            self.pos = bgn + n + 2;
+                            return Ok(Tok::SymbolNameLower(&rest[..n]));
+                        }
+                        b'A'..=b'Z' => {
+                            self.pos = bgn + n + 2;
+                            return Ok(Tok::SymbolNameUpper(&rest[..n]));
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            // "-" and "/" are a flat and a repeat in "{...}", so a signed number or a fraction is written
+            // in parentheses there, e.g. "(-3/8)".  a plain integer in parentheses remains a chord and the
+            // parentheses after a parametric curve are its parameter, e.g. "{bezier}(1/2)".
+            '(' if self.depth > 0 && !self.curve => {
+                let rest = &self.src[bgn + 1..];
+                let n = Self::number_len(rest);
+                if n > 0 && rest[n..].starts_with(')') && !rest[..n].bytes().all(|c| c.is_ascii_digit()) {
+                    self.pos = bgn + n + 2;
+                    return Ok(Tok::Number(&rest[..n]));
+                }
+            }
+            '\\' if self.depth > 0 => {
+                self.pos += 1;
+                if self.src[self.pos..].starts_with(['<', '>']) {
+                    self.pos += 1;
+                } else {
+                    self.skip_while(|c| c.is_ascii_alphabetic());
+                }
+                if self.pos == bgn + 1 {
+                    return misc::error(self.path, bgn, "unexpected token.");
+                }
+                return Ok(Tok::Dynamic(&self.src[bgn + 1..self.pos]));
+            }
+            '`' => {
+                self.pos += 1;
+                self.skip_while(|c| c != ' ' && c != '\t' && c != '\n');
+                if self.pos == bgn + 1 {
+                    return misc::error(self.path, bgn, "unexpected token.");
+                }
+                return Ok(Tok::ChordSymbol(&self.src[bgn..self.pos]));
+            }
+            '"' => {
+                let mut it = self.src[bgn + 1..].char_indices();
+                loop {
+                    match it.next() {
+                        Some((i, '"')) => {
+                            self.pos = bgn + i + 2;
+                            return Ok(Tok::Str(&self.src[bgn..self.pos]));
+                        }
+                        Some((i, '\\')) => match it.next() {
+                            Some((_, 'n')) | Some((_, 'r')) | Some((_, 't')) | Some((_, '\\')) => (),
+                            _ => return misc::error(self.path, bgn + i + 1, "invalid escape sequence."),
+                        },
+                        Some(_) => (),
+                        None => return misc::error(self.path, bgn, "unterminated string."),
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        let rest = &self.src[self.pos..];
+        match PUNCTUATIONS.iter().find(|&&p| rest.starts_with(p)) {
+            Some(p) => {
+                self.pos += p.len();
+                match *p {
+                    "{" => self.depth += 1,
+                    "}" => self.depth = self.depth.saturating_sub(1),
+                    _ => (),
+                }
+                Ok(Tok::Keyword(&rest[..p.len()]))
+            }
+            None => misc::error(self.path, bgn, "unexpected token."),
+        }
+    }
+}
+
+// returns the tokens with the comments before them and the trailing comments, which the parser
+// ignores, e.g. for formatters.
+pub fn tokenize<'a>(path: &'a path::Path, src: &'a str) -> Result<(Vec<Token<'a>>, Vec<(usize, usize)>), misc::Error> {
+    let mut lexer = Lexer::new(path, src);
+    let tokens = lexer.by_ref().collect::<Result<Vec<_>, _>>()?;
+    Ok((tokens, lexer.comments))
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Token<'a>, misc::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.skip_trivia() {
+            self.pos = self.src.len();
+            return Some(Err(e));
+        }
+        if self.pos >= self.src.len() {
+            return None;
+        }
+        let bgn = self.pos;
+        match self.next_tok() {
+            Ok(tok) => {
+                self.curve = matches!(tok, Tok::SymbolNameLower(v) if PARAMETRIC_CURVES.contains(&v));
+                Some(Ok(Token {
+                    bgn: bgn,
+                    end: self.pos,
+                    tok: tok,
+                    comments: mem::take(&mut self.comments),
+                }))
+            }
+            Err(e) => {
+                self.pos = self.src.len();
+                Some(Err(e))
+            }
+        }
+    }
+}
@@ -2,11 +2,14 @@
 pub mod ast;
 pub mod chord;
 pub mod generator;
+pub mod lexer;
 pub mod midi;
 pub mod misc;
 pub mod random;
 pub mod ratio;
+pub mod scale;
 pub mod smf;
+pub mod source;
 #[cfg(test)]
 pub mod test;
 pub mod voicing;
@@ -16,36 +19,171 @@ use std::*;
 pub mod parser {
     include!(concat!(env!("OUT_DIR"), "/parser.rs"));
 
-    // XXX
-    fn remove_comments(src: &str) -> borrow::Cow<'_, str> {
-        use regex::*;
-        thread_local!(static RE: Regex = Regex::new(r"(?s:/\*.*?\*/)").unwrap());
-        RE.with(|re| {
-            re.replace_all(src, |caps: &Captures<'_>| {
-                caps.get(0)
-                    .unwrap()
-                    .as_str()
-                    .chars()
-                    .map(|c| if c == '\r' || c == '\n' { c } else { ' ' })
-                    .collect::<String>()
-            })
-        })
+    // parses each file at most once per compilation and detects cyclic imports.
+    pub struct Importer<'a> {
+        src: &'a dyn crate::source::Provider,
+        cache: cell::RefCell<collections::HashMap<path::PathBuf, Definition<'a>>>,
+        stack: cell::RefCell<Vec<(path::PathBuf, path::PathBuf)>>,
+        texts: cell::RefCell<collections::HashMap<path::PathBuf, sync::Arc<str>>>,
+        diags: cell::RefCell<misc::Diagnostics>,
     }
 
-    pub fn parse<'a>(path: &path::Path) -> Result<Definition<'a>, misc::Error> {
-        let buf = fs::read_to_string(path).map_err(|e| misc::Error::new(path, 0, format!("{}", e)))?;
-
-        thread_local!(static PARSER: definitionParser = definitionParser::new());
-        PARSER.with(|parser| match parser.parse(path, &remove_comments(&buf)) {
-            Ok(v) => Ok(v),
-            Err(e) => match e {
-                ParseError::InvalidToken { location: i }
-                | ParseError::UnrecognizedToken { token: (i, _, _), .. }
-                | ParseError::ExtraToken { token: (i, _, _) } => misc::error(path, i, "unexpected token."),
-                ParseError::UnrecognizedEof { .. } => misc::error(path, buf.len(), "unexpected EOF."),
-                ParseError::User { error: err } => Err(err),
-            },
-        })
+    // converts a parse error, which may have been recovered from, into ours.
+    fn convert_error(path: &path::Path, err: ParseError<usize, crate::lexer::Tok<'_>, misc::Error>) -> misc::Error {
+        match err {
+            ParseError::InvalidToken { location: i } => misc::Error::new(path, i, "unexpected token."),
+            ParseError::UnrecognizedToken { token: (i, _, j), .. } | ParseError::ExtraToken { token: (i, _, j) } => {
+                misc::Error::range(path, i, j, "unexpected token.")
+            }
+            ParseError::UnrecognizedEof { location: i, .. } => misc::Error::new(path, i, "unexpected EOF."),
+            ParseError::User { error: err } => err,
+        }
+    }
+
+    impl<'a> Importer<'a> {
+        pub fn new(src: &'a dyn crate::source::Provider) -> Self {
+            Importer {
+                src: src,
+                cache: cell::RefCell::new(collections::HashMap::new()),
+                stack: cell::RefCell::new(Vec::new()),
+                texts: cell::RefCell::new(collections::HashMap::new()),
+                diags: cell::RefCell::new(misc::Diagnostics::new()),
+            }
+        }
+
+        pub fn parse(&self, path: &path::Path) -> Result<Definition<'a>, misc::Error> {
+            let key = self
+                .src
+                .canonicalize(path)
+                .map_err(|e| misc::Error::new(path, 0, format!("{}", e)))?;
+            if let Some(def) = self.cache.borrow().get(&key) {
+                return Ok(def.clone());
+            }
+            let buf = self
+                .src
+                .read(path)
+                .map_err(|e| misc::Error::new(path, 0, format!("{}", e)))?;
+            self.texts.borrow_mut().insert(path.to_owned(), buf.as_str().into());
+
+            self.stack.borrow_mut().push((key.clone(), path.to_owned()));
+            thread_local!(static PARSER: definitionParser = definitionParser::new());
+            let tokens = crate::lexer::Lexer::new(path, &buf).map(|e| e.map(|t| (t.bgn, t.tok, t.end)));
+            let result = PARSER.with(|parser| parser.parse(path, self, tokens).map_err(|e| convert_error(path, e)));
+            self.stack.borrow_mut().pop();
+
+            let def = result?;
+            self.cache.borrow_mut().insert(key, def.clone());
+            Ok(def)
+        }
+
+        // parses the root file of a compilation, reporting the error instead of returning it.
+        pub fn parse_root(&self, path: &path::Path) -> Option<Definition<'a>> {
+            match self.parse(path) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    self.report(e);
+                    None
+                }
+            }
+        }
+
+        // merges the definitions of "src" into "dst", prefixing them with "ns".  definitions which
+        // are imported more than once through different paths are merged silently.  errors are
+        // reported and the rest is imported as far as possible.
+        pub fn import(
+            &self,
+            dst: &mut Definition<'a>,
+            path: &path::Path,
+            bgn: usize,
+            end: usize,
+            src: &path::Path,
+            ns: &str,
+        ) {
+            if let Ok(key) = self.src.canonicalize(src) {
+                let stack = self.stack.borrow();
+                if let Some(i) = stack.iter().position(|e| e.0 == key) {
+                    let mut cycle: Vec<_> = stack[i..].iter().map(|e| e.1.to_string_lossy()).collect();
+                    cycle.push(src.to_string_lossy());
+                    let msg = format!("cyclic import: {}.", cycle.join(" -> "));
+                    self.report(misc::Error::range(path, bgn, end, msg));
+                    return;
+                }
+            }
+
+            let def = match self.parse(src) {
+                Ok(v) => v,
+                Err(e) => {
+                    self.report(e);
+                    return;
+                }
+            };
+            for (dst, src) in [(&mut dst.scores, def.scores), (&mut dst.values, def.values)] {
+                for (key, f) in src.into_iter() {
+                    let f = Function {
+                        ns: format!("{}{}", ns, f.ns),
+                        ..f
+                    };
+                    match dst.entry(format!("{}{}", ns, key)) {
+                        collections::hash_map::Entry::Occupied(e) => {
+                            let g = e.get();
+                            if g.path != f.path {
+                                let msg = format!("duplicated definition: ${}.", e.key());
+                                let err = misc::Error::range(path, bgn, end, msg)
+                                    .label(&g.path, g.bgn, g.end, "previous definition here.")
+                                    .label(&f.path, f.bgn, f.end, "imported definition here.");
+                                self.report(err);
+                            }
+                        }
+                        collections::hash_map::Entry::Vacant(e) => {
+                            e.insert(f);
+                        }
+                    }
+                }
+            }
+
+            // the key is shared with the files imported without a namespace, like the definitions.
+            if let (Some(f), "") = (def.key, ns) {
+                match dst.key {
+                    Some(ref g) if g.path != f.path => {
+                        let err = misc::Error::range(path, bgn, end, "duplicated definition: key.")
+                            .label(&g.path, g.bgn, g.end, "previous definition here.")
+                            .label(&f.path, f.bgn, f.end, "imported definition here.");
+                        self.report(err);
+                    }
+                    Some(_) => (),
+                    None => dst.key = Some(f),
+                }
+            }
+        }
+
+        // records an error which the parser has recovered from.
+        pub fn report(&self, err: misc::Error) {
+            self.diags.borrow_mut().push(err);
+        }
+
+        pub fn sources(&self) -> collections::HashMap<path::PathBuf, sync::Arc<str>> {
+            self.texts.borrow().clone()
+        }
+
+        // takes the diagnostics reported so far, attaching the source texts which they refer to.
+        pub fn take_diagnostics(&self) -> misc::Diagnostics {
+            let mut diags = mem::take(&mut *self.diags.borrow_mut());
+            diags.attach(&self.texts.borrow());
+            diags
+        }
+    }
+
+    pub fn parse<'a>(
+        src: &'a dyn crate::source::Provider,
+        path: &path::Path,
+    ) -> Result<Definition<'a>, misc::Diagnostics> {
+        let importer = Importer::new(src);
+        let tree = importer.parse_root(path);
+        let diags = importer.take_diagnostics();
+        match tree {
+            Some(v) if !diags.has_errors() => Ok(v),
+            _ => Err(diags),
+        }
     }
 }
 
@@ -66,6 +204,8 @@ pub struct Assembly {
     pub len: Ratio,
     pub bgn: Ratio,
     pub end: Ratio,
+    // the source texts, which the diagnostics from "assemble()" refer to.
+    pub sources: collections::HashMap<path::PathBuf, sync::Arc<str>>,
 }
 
 impl default::Default for Assembly {
@@ -76,19 +216,47 @@ impl default::Default for Assembly {
             len: Ratio::zero(),
             bgn: Ratio::zero(),
             end: Ratio::zero(),
+            sources: collections::HashMap::new(),
         }
     }
 }
 
 pub const TICK: i64 = 240;
 
-pub fn compile(rng: &random::Generator, src: &path::Path) -> Result<Assembly, misc::Error> {
-    let tree = parser::parse(src)?;
-    let gen = generator::Generator::new(rng, &tree);
+pub fn compile(rng: &random::Generator, path: &path::Path) -> Result<Assembly, misc::Diagnostics> {
+    compile_from(rng, &source::FileProvider, path)
+}
 
+// reports all the errors found, continuing the compilation as far as possible.
+pub fn compile_from(
+    rng: &random::Generator,
+    src: &dyn source::Provider,
+    path: &path::Path,
+) -> Result<Assembly, misc::Diagnostics> {
+    let importer = parser::Importer::new(src);
+    let tree = importer.parse_root(path);
+    let asm = tree.as_ref().map(|tree| {
+        let gen = generator::Generator::new(rng, tree);
+        let asm = generate(rng, &gen);
+        for e in gen.take_diagnostics().items.into_iter() {
+            importer.report(e);
+        }
+        Assembly {
+            sources: importer.sources(),
+            ..asm
+        }
+    });
+    let diags = importer.take_diagnostics();
+    match asm {
+        Some(v) if !diags.has_errors() => Ok(v),
+        _ => Err(diags),
+    }
+}
+
+fn generate<'a>(rng: &'a random::Generator, gen: &generator::Generator<'a>) -> Assembly {
     let mut scores = Vec::new();
     for ch in 0..16 {
-        if let Some(ir) = gen.generate_score(&format!("out.{}", ch))? {
+        if let Some(ir) = gen.generate_score(&format!("out.{}", ch)) {
             scores.push((ch, ir));
         }
     }
@@ -96,7 +264,7 @@ pub fn compile(rng: &random::Generator, src: &path::Path) -> Result<Assembly, mi
     let mut channels = Vec::new();
     for (ch, score) in scores.into_iter() {
         let velocity = gen
-            .generate_value(&format!("out.{}.velocity", ch))?
+            .generate_value(&format!("out.{}.velocity", ch))
             .unwrap_or(generator::ValueIr::Value(
                 Ratio::zero(),
                 Ratio::one(),
@@ -104,7 +272,7 @@ pub fn compile(rng: &random::Generator, src: &path::Path) -> Result<Assembly, mi
                 Ratio::new(5, 8),
             ));
         let offset = gen
-            .generate_value(&format!("out.{}.offset", ch))?
+            .generate_value(&format!("out.{}.offset", ch))
             .unwrap_or(generator::ValueIr::Value(
                 Ratio::zero(),
                 Ratio::one(),
@@ -112,10 +280,10 @@ pub fn compile(rng: &random::Generator, src: &path::Path) -> Result<Assembly, mi
                 Ratio::zero(),
             ));
         let duration = gen
-            .generate_value(&format!("out.{}.duration", ch))?
+            .generate_value(&format!("out.{}.duration", ch))
             .unwrap_or(generator::ValueIr::NoteLen);
         let pitch = gen
-            .generate_value(&format!("out.{}.pitch", ch))?
+            .generate_value(&format!("out.{}.pitch", ch))
             .unwrap_or(generator::ValueIr::Value(
                 Ratio::zero(),
                 Ratio::one(),
@@ -124,7 +292,7 @@ pub fn compile(rng: &random::Generator, src: &path::Path) -> Result<Assembly, mi
             ));
         let mut ccs = Vec::new();
         for cc in 0..128 {
-            if let Some(ir) = gen.generate_value(&format!("out.{}.cc{}", ch, cc))? {
+            if let Some(ir) = gen.generate_value(&format!("out.{}.cc{}", ch, cc)) {
                 ccs.push((cc, ir));
             }
         }
@@ -141,7 +309,7 @@ pub fn compile(rng: &random::Generator, src: &path::Path) -> Result<Assembly, mi
         ));
     }
 
-    let tempo = gen.generate_value("out.tempo")?.unwrap_or(generator::ValueIr::Value(
+    let tempo = gen.generate_value("out.tempo").unwrap_or(generator::ValueIr::Value(
         Ratio::zero(),
         Ratio::one(),
         Ratio::new(1, 2),
@@ -156,35 +324,39 @@ pub fn compile(rng: &random::Generator, src: &path::Path) -> Result<Assembly, mi
         .unwrap_or(Ratio::zero());
 
     let evaluator = generator::Evaluator::new(rng);
-    let bgn = match gen.generate_value("out.begin")? {
+    let bgn = match gen.generate_value("out.begin") {
         Some(ir) => (evaluator.eval(&ir, Ratio::zero()) * TICK as f64).round() as i64,
         None => 0,
     };
-    let end = match gen.generate_value("out.end")? {
+    let end = match gen.generate_value("out.end") {
         Some(ir) => (evaluator.eval(&ir, Ratio::zero()) * TICK as f64).round() as i64,
         None => (len * TICK).round(),
     };
 
-    Ok(Assembly {
+    Assembly {
         channels: channels,
         tempo: tempo,
         len: len,
         bgn: Ratio::new(bgn, TICK),
         end: Ratio::new(end, TICK),
-    })
+        sources: collections::HashMap::new(),
+    }
 }
 
-pub fn assemble(rng: &random::Generator, src: &Assembly) -> Result<Vec<midi::Event>, misc::Error> {
+// returns the MIDI events with the warnings about the musical data which are clamped or dropped.
+pub fn assemble(rng: &random::Generator, src: &Assembly) -> (Vec<midi::Event>, misc::Diagnostics) {
     let bgn = (src.bgn * TICK).round();
     let end = (src.end * TICK).round();
     let mut migen = midi::Generator::new(rng, bgn, end, TICK);
     for &(ch, ref irs) in src.channels.iter() {
         migen.add_score(ch, &irs.score, &irs.velocity, &irs.offset, &irs.duration);
-        migen.add_pitch(ch, &irs.pitch);
+        migen.add_pitch(ch, &irs.score, &irs.pitch);
         for &(cc, ref ir) in irs.ccs.iter() {
-            migen.add_cc(ch, cc, &ir);
+            migen.add_cc(ch, cc, &irs.score, &ir);
         }
     }
     migen.add_tempo(&src.tempo);
-    Ok(migen.generate()?)
+    let (events, mut diags) = migen.generate();
+    diags.attach(&src.sources);
+    (events, diags)
 }
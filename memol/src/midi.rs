@@ -36,6 +36,35 @@ impl Event {
     }
 }
 
+// lets "$note.*()" in a value lane refer to the latest note started, or the highest of the ones
+// started at the same time.
+struct NoteFollower<'a> {
+    score: &'a generator::ScoreIr,
+    notes: Vec<&'a generator::FlatNote>,
+    next: usize,
+}
+
+impl<'a> NoteFollower<'a> {
+    fn new(score: &'a generator::ScoreIr) -> Self {
+        let mut notes: Vec<_> = score.iter().filter(|f| f.nnum.is_some()).collect();
+        notes.sort_by_key(|f| (f.t0, f.nnum));
+        NoteFollower {
+            score: score,
+            notes: notes,
+            next: 0,
+        }
+    }
+
+    // must be called with non-decreasing times.
+    fn advance(&mut self, evaluator: &mut generator::Evaluator<'_>, t: Ratio) {
+        let n = self.notes[self.next..].iter().take_while(|f| f.t0 <= t).count();
+        if n > 0 {
+            self.next += n;
+            evaluator.set_note(self.score, self.notes[self.next - 1]);
+        }
+    }
+}
+
 pub struct Generator<'a> {
     rng: &'a random::Generator,
     events: Vec<Event>,
@@ -43,6 +72,7 @@ pub struct Generator<'a> {
     bgn: i64,
     end: i64,
     tick: i64,
+    diags: misc::Diagnostics,
 }
 
 impl<'a> Generator<'a> {
@@ -54,6 +84,14 @@ impl<'a> Generator<'a> {
             bgn: bgn,
             end: end,
             tick: tick,
+            diags: misc::Diagnostics::new(),
+        }
+    }
+
+    fn warn<T: Into<String>>(&mut self, f: &generator::FlatNote, msg: T) {
+        if let Some(ref src) = f.src {
+            self.diags
+                .push(misc::Diagnostic::warning(&src.path, src.bgn, src.end, msg));
         }
     }
 
@@ -66,18 +104,43 @@ impl<'a> Generator<'a> {
         ir_dur: &generator::ValueIr,
     ) {
         let mut evaluator = generator::Evaluator::new(self.rng);
+        evaluator.set_score(ir_score);
         let mut offset = collections::HashMap::new();
+        let mut notes = Vec::new();
+        let bgn = Ratio::new(self.bgn, self.tick);
+        let end = Ratio::new(self.end, self.tick);
         for f in ir_score.iter() {
             let nnum = match f.nnum {
                 Some(v) => v,
                 None => continue,
             };
-            // accepts note off messages at end.
-            if f.t0 < Ratio::new(self.bgn, self.tick) || Ratio::new(self.end, self.tick) < f.t1 {
+            // accepts note off messages at end.  a negative "out.begin" includes the notes before 0.
+            if f.t0 < cmp::min(bgn, Ratio::zero()) {
+                self.warn(f, "note before the beginning of the score is dropped.");
+                continue;
+            }
+            if f.t0 < bgn || end < f.t1 {
+                // the range is the whole score unless "out.begin" and "out.end" are specified, so the
+                // notes entirely out of the range are the ones cut intentionally by them.
+                if f.t0 < end && bgn < f.t1 {
+                    self.warn(f, "note across the boundary of the output range is dropped.");
+                }
+                continue;
+            }
+            if !(0..128).contains(&nnum) {
+                self.warn(f, format!("note number out of range: {}.", nnum));
                 continue;
             }
 
             evaluator.set_note(ir_score, f);
+
+            // a dynamic scales the velocity and an accent raises it by 1/8.  the velocity is
+            // evaluated first, which "$note.velocity()" in the offset and the duration refers to.
+            let vel = evaluator.eval(ir_vel, f.t0) * f.marks.dynamic.map(|v| v.to_float()).unwrap_or(1.0);
+            let vel = if f.marks.accent { vel + 0.125 } else { vel };
+            let vel = (vel * 127.0).round();
+            evaluator.note_velocity = vel.clamp(0.0, 127.0) / 127.0;
+
             let dt = evaluator.eval(ir_dur, f.t0);
             let d0 = *offset
                 .entry((f.t0, nnum))
@@ -86,27 +149,62 @@ impl<'a> Generator<'a> {
                 .entry((f.t1, nnum))
                 .or_insert_with(|| evaluator.eval(ir_ofs, f.t1));
 
-            // be careful of the numerical error which causes order inversion.
-            let a = dt / evaluator.note_len;
+            // be careful of the numerical error which causes order inversion.  a tenuto holds the
+            // whole note and a staccato halves it.
+            let a = if f.marks.tenuto { 1.0 } else { dt / evaluator.note_len };
+            let a = if f.marks.staccato { a * 0.5 } else { a };
             let t0 = f.t0.to_float() + d0;
             let t1 = (1.0 - a) * t0 + a * (f.t1.to_float() + d1);
-            if t0 >= t1 {
+            // a note of zero length gives NaN, which would break the sort of the events.
+            if t1.is_nan() || t0 >= t1 {
+                self.warn(f, "note of non-positive length after offset and duration is dropped.");
                 continue;
             }
 
-            let vel = (evaluator.eval(ir_vel, f.t0) * 127.0).round().max(0.0).min(127.0);
+            if !(0.0..=127.0).contains(&vel) {
+                self.warn(f, "velocity out of range is clamped.");
+            }
+            let vel = vel.max(0.0).min(127.0);
             self.events
                 .push(Event::new(t0, 1, &[(0x90 + ch) as u8, nnum as u8, vel as u8]));
             self.events
                 .push(Event::new(t1, -1, &[(0x80 + ch) as u8, nnum as u8, vel as u8]));
+            notes.push((nnum, t0, t1, f));
+        }
+
+        // a note-off of one note terminates the other one.
+        notes.sort_by(|x, y| (x.0, x.1).partial_cmp(&(y.0, y.1)).unwrap());
+        let mut last: Option<(i64, f64, &generator::FlatNote)> = None;
+        for &(nnum, t0, t1, f) in notes.iter() {
+            match last {
+                Some((n, t, g)) if n == nnum && t0 < t => {
+                    if let (Some(ref src0), Some(ref src1)) = (&g.src, &f.src) {
+                        let msg = "overlapping notes of the same pitch.";
+                        let diag = misc::Diagnostic::warning(&src1.path, src1.bgn, src1.end, msg).label(
+                            &src0.path,
+                            src0.bgn,
+                            src0.end,
+                            "overlapped note here.",
+                        );
+                        self.diags.push(diag);
+                    }
+                    if t < t1 {
+                        last = Some((nnum, t1, f));
+                    }
+                }
+                _ => last = Some((nnum, t1, f)),
+            }
         }
     }
 
-    pub fn add_pitch(&mut self, ch: usize, ir: &generator::ValueIr) {
-        let evaluator = generator::Evaluator::new(self.rng);
+    pub fn add_pitch(&mut self, ch: usize, ir_score: &generator::ScoreIr, ir: &generator::ValueIr) {
+        let mut evaluator = generator::Evaluator::new(self.rng);
+        evaluator.set_score(ir_score);
+        let mut follower = NoteFollower::new(ir_score);
         let mut prev_v = 8192;
         for i in self.bgn..self.end {
             let t = Ratio::new(i, self.tick);
+            follower.advance(&mut evaluator, t);
             let v = (evaluator.eval(ir, t) * 8192.0 + 8192.0).round().max(0.0).min(16383.0) as usize;
             if v != prev_v {
                 let lsb = ((v >> 0) & 0x7f) as u8;
@@ -118,11 +216,14 @@ impl<'a> Generator<'a> {
         }
     }
 
-    pub fn add_cc(&mut self, ch: usize, cc: usize, ir: &generator::ValueIr) {
-        let evaluator = generator::Evaluator::new(self.rng);
+    pub fn add_cc(&mut self, ch: usize, cc: usize, ir_score: &generator::ScoreIr, ir: &generator::ValueIr) {
+        let mut evaluator = generator::Evaluator::new(self.rng);
+        evaluator.set_score(ir_score);
+        let mut follower = NoteFollower::new(ir_score);
         let mut prev_v = 255;
         for i in self.bgn..self.end {
             let t = Ratio::new(i, self.tick);
+            follower.advance(&mut evaluator, t);
             let v = (evaluator.eval(ir, t) * 127.0).round().max(0.0).min(127.0) as u8;
             if v != prev_v {
                 self.events
@@ -148,7 +249,7 @@ impl<'a> Generator<'a> {
         self.timeline.push(s);
     }
 
-    pub fn generate(mut self) -> Result<Vec<Event>, misc::Error> {
+    pub fn generate(mut self) -> (Vec<Event>, misc::Diagnostics) {
         self.events
             .sort_by(|x, y| (x.time, x.prio).partial_cmp(&(y.time, y.prio)).unwrap());
         if self.timeline.len() > 0 {
@@ -161,6 +262,6 @@ impl<'a> Generator<'a> {
                 ev.time = (1.0 - a) * f0 + a * f1;
             }
         }
-        Ok(self.events)
+        (self.events, self.diags)
     }
 }
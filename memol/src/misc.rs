@@ -53,55 +53,282 @@ pub fn bsearch_boundary<T, F: FnMut(&T) -> bool>(xs: &[T], mut f: F) -> usize {
     lo
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match *self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        })
+    }
+}
+
+// a secondary location which explains a diagnostic, e.g. a previous definition.
 #[derive(Debug)]
-pub struct Error {
+pub struct Label {
     pub path: path::PathBuf,
-    pub index: usize,
+    pub bgn: usize,
+    pub end: usize,
     pub message: String,
+    pub text: Option<sync::Arc<str>>,
 }
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let path_str = self.path.to_string_lossy();
-        match fs::read_to_string(&self.path) {
-            Ok(buf) => {
-                let mut row = 0;
-                let mut col = 0;
-                for c in buf.chars().take(self.index) {
-                    match c {
-                        '\r' => (),
-                        '\n' => {
-                            row += 1;
-                            col = 0;
-                        }
-                        _ => {
-                            col += 1;
-                        }
-                    }
-                }
-                write!(f, "{}:{}:{}: {}", path_str, row, col, self.message)
-            }
-            Err(_) => write!(f, "{}: {}", path_str, self.message),
-        }
-    }
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub path: path::PathBuf,
+    // the byte range in the source text.
+    pub bgn: usize,
+    pub end: usize,
+    pub message: String,
+    pub text: Option<sync::Arc<str>>,
+    pub labels: Vec<Label>,
 }
 
-impl error::Error for Error {
-    fn description(&self) -> &str {
-        panic!();
+// most of the diagnostics are errors, which abort the generation of a definition.
+pub type Error = Diagnostic;
+
+// returns the 1-based row and column of "idx", and the line containing it split at "idx".
+fn locate(text: &str, idx: usize) -> (usize, usize, &str, &str) {
+    let mut idx = cmp::min(idx, text.len());
+    while !text.is_char_boundary(idx) {
+        idx -= 1;
     }
+    let bol = text[..idx].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let eol = text[idx..].find('\n').map(|i| idx + i).unwrap_or(text.len());
+    let row = text[..bol].matches('\n').count() + 1;
+    let col = text[bol..idx].chars().filter(|&c| c != '\r').count() + 1;
+    (row, col, &text[bol..idx], text[idx..eol].trim_end_matches('\r'))
 }
 
-impl Error {
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const BLUE: &str = "\x1b[1;34m";
+
+// writes the source line with the range underlined by "mark".
+fn render_snippet(dst: &mut String, l: &Label, mark: char, width: usize, color: Option<&str>) {
+    let (c0, c1, c2) = match color {
+        Some(c) => (c, BLUE, RESET),
+        None => ("", "", ""),
+    };
+    let path_str = l.path.to_string_lossy();
+    let text = match l.text {
+        Some(ref v) => v,
+        None => {
+            dst.push_str(&format!("{:w$}{}-->{} {}\n", "", c1, c2, path_str, w = width));
+            return;
+        }
+    };
+    let (row, col, head, tail) = locate(text, l.bgn);
+    let len = tail
+        .char_indices()
+        .take_while(|&(i, _)| i < l.end.saturating_sub(l.bgn))
+        .count();
+    // keep tabs to align the underline with the line.
+    let indent: String = head.chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    let marks = mark.to_string().repeat(cmp::max(len, 1));
+    let message = if l.message.is_empty() {
+        String::new()
+    } else {
+        format!(" {}", l.message)
+    };
+    dst.push_str(&format!(
+        "{:w$}{}-->{} {}:{}:{}\n",
+        "",
+        c1,
+        c2,
+        path_str,
+        row,
+        col,
+        w = width
+    ));
+    dst.push_str(&format!("{:w$} {}|{}\n", "", c1, c2, w = width));
+    dst.push_str(&format!("{}{:w$} |{} {}{}\n", c1, row, c2, head, tail, w = width));
+    dst.push_str(&format!(
+        "{:w$} {}|{} {}{}{}{}{}\n",
+        "",
+        c1,
+        c2,
+        indent,
+        c0,
+        marks,
+        message,
+        c2,
+        w = width
+    ));
+}
+
+impl Diagnostic {
     pub fn new<T: Into<String>>(path: &path::Path, idx: usize, msg: T) -> Self {
-        Error {
+        Diagnostic::range(path, idx, idx, msg)
+    }
+
+    pub fn range<T: Into<String>>(path: &path::Path, bgn: usize, end: usize, msg: T) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            path: path.to_owned(),
+            bgn: bgn,
+            end: end,
+            message: msg.into(),
+            text: None,
+            labels: Vec::new(),
+        }
+    }
+
+    pub fn warning<T: Into<String>>(path: &path::Path, bgn: usize, end: usize, msg: T) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::range(path, bgn, end, msg)
+        }
+    }
+
+    pub fn label<T: Into<String>>(mut self, path: &path::Path, bgn: usize, end: usize, msg: T) -> Self {
+        self.labels.push(Label {
             path: path.to_owned(),
-            index: idx,
+            bgn: bgn,
+            end: end,
             message: msg.into(),
+            text: None,
+        });
+        self
+    }
+
+    // renders the diagnostic with the source lines, optionally colored by ANSI escape sequences.
+    pub fn render(&self, color: bool) -> String {
+        let c0 = match self.severity {
+            Severity::Error => "\x1b[1;31m",
+            Severity::Warning => "\x1b[1;33m",
+        };
+        let primary = Label {
+            path: self.path.clone(),
+            bgn: self.bgn,
+            end: self.end,
+            message: String::new(),
+            text: self.text.clone(),
+        };
+        let labels: Vec<_> = iter::once(&primary).chain(self.labels.iter()).collect();
+        let width = labels
+            .iter()
+            .filter_map(|l| l.text.as_ref().map(|t| locate(t, l.bgn).0.to_string().len()))
+            .max()
+            .unwrap_or(0);
+
+        let mut dst = match color {
+            true => format!("{}{}{}: {}{}{}\n", c0, self.severity, RESET, BOLD, self.message, RESET),
+            false => format!("{}: {}\n", self.severity, self.message),
+        };
+        render_snippet(&mut dst, &primary, '^', width, if color { Some(c0) } else { None });
+        for l in labels[1..].iter() {
+            render_snippet(&mut dst, l, '-', width, if color { Some(BLUE) } else { None });
+        }
+        dst
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path_str = self.path.to_string_lossy();
+        match self.text {
+            Some(ref text) => {
+                let (row, col, _, _) = locate(text, self.bgn);
+                write!(f, "{}:{}:{}: {}: {}", path_str, row, col, self.severity, self.message)
+            }
+            None => write!(f, "{}: {}: {}", path_str, self.severity, self.message),
         }
     }
 }
 
+impl error::Error for Diagnostic {}
+
 pub fn error<T: convert::Into<String>, U, V: From<Error>>(path: &path::Path, idx: usize, msg: T) -> Result<U, V> {
     Err(From::from(Error::new(path, idx, msg)))
 }
+
+pub fn error_range<T: convert::Into<String>, U, V: From<Error>>(
+    path: &path::Path,
+    bgn: usize,
+    end: usize,
+    msg: T,
+) -> Result<U, V> {
+    Err(From::from(Error::range(path, bgn, end, msg)))
+}
+
+// the diagnostics collected through a compilation, which continues as far as possible after an
+// error.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    pub items: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Diagnostics { items: Vec::new() }
+    }
+
+    // ignores the diagnostic reported at the same position again, e.g. in a repeated score.
+    pub fn push(&mut self, diag: Diagnostic) {
+        let dup = self.items.iter().any(|e| {
+            e.severity == diag.severity && e.path == diag.path && e.bgn == diag.bgn && e.message == diag.message
+        });
+        if !dup {
+            self.items.push(diag);
+        }
+    }
+
+    pub fn append(&mut self, other: Diagnostics) {
+        for e in other.items.into_iter() {
+            self.push(e);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.items.iter().any(|e| e.severity == Severity::Error)
+    }
+
+    // attaches the source texts which the diagnostics refer to.
+    pub fn attach(&mut self, texts: &collections::HashMap<path::PathBuf, sync::Arc<str>>) {
+        for e in self.items.iter_mut() {
+            e.text = texts.get(&e.path).cloned();
+            for l in e.labels.iter_mut() {
+                l.text = texts.get(&l.path).cloned();
+            }
+        }
+    }
+
+    pub fn render(&self, color: bool) -> String {
+        self.items
+            .iter()
+            .map(|e| e.render(color))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, e) in self.items.iter().enumerate() {
+            if i != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Diagnostics {}
+
+impl From<Diagnostic> for Diagnostics {
+    fn from(diag: Diagnostic) -> Self {
+        Diagnostics { items: vec![diag] }
+    }
+}
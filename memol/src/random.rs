@@ -26,6 +26,15 @@ impl Generator {
         }
     }
 
+    // the state, which can be restored to draw the same numbers again.
+    pub fn state(&self) -> u64 {
+        self.s.get()
+    }
+
+    pub fn set_state(&self, s: u64) {
+        self.s.set(s);
+    }
+
     pub fn next_u64(&self) -> u64 {
         let z = self.s.get();
         self.s.set(z.wrapping_mul(Self::M) + self.a);
@@ -15,6 +15,29 @@ impl From<i64> for Ratio {
     }
 }
 
+// parses "-?[0-9]+", "-?[0-9]+\.[0-9]+" and "-?[0-9]+/[0-9]+" exactly.
+impl str::FromStr for Ratio {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let int = |s: &str| s.parse::<i64>().map_err(|_| "invalid number.");
+        if let Some(i) = s.find('/') {
+            let x = int(&s[i + 1..])?;
+            if x == 0 {
+                return Err("division by zero.");
+            }
+            return Ok(Ratio::new(int(&s[..i])?, x));
+        }
+        if let Some(i) = s.find('.') {
+            let frac = &s[i + 1..];
+            let y = int(&format!("{}{}", &s[..i], frac))?;
+            let x = int(&format!("1{}", "0".repeat(frac.len())))?;
+            return Ok(Ratio::new(y, x));
+        }
+        Ok(Ratio::from(int(s)?))
+    }
+}
+
 impl cmp::PartialEq for Ratio {
     fn eq(&self, other: &Self) -> bool {
         self.y * other.x == other.y * self.x
@@ -0,0 +1,56 @@
+// (c) Yasuhiro Fujii <http://mimosa-pudica.net>, under MIT License.
+use crate::misc;
+use std::*;
+
+// the intervals from the root.
+const SCALES: &[(&str, &[i64])] = &[
+    ("major", &[0, 2, 4, 5, 7, 9, 11]),
+    ("minor", &[0, 2, 3, 5, 7, 8, 10]),
+    ("harmonic_minor", &[0, 2, 3, 5, 7, 8, 11]),
+    ("melodic_minor", &[0, 2, 3, 5, 7, 9, 11]),
+    ("ionian", &[0, 2, 4, 5, 7, 9, 11]),
+    ("dorian", &[0, 2, 3, 5, 7, 9, 10]),
+    ("phrygian", &[0, 1, 3, 5, 7, 8, 10]),
+    ("lydian", &[0, 2, 4, 6, 7, 9, 11]),
+    ("mixolydian", &[0, 2, 4, 5, 7, 9, 10]),
+    ("aeolian", &[0, 2, 3, 5, 7, 8, 10]),
+    ("locrian", &[0, 1, 3, 5, 6, 8, 10]),
+    ("pentatonic", &[0, 2, 4, 7, 9]),
+    ("minor_pentatonic", &[0, 3, 5, 7, 10]),
+    ("whole_tone", &[0, 2, 4, 6, 8, 10]),
+];
+
+// the pitch classes of "a" .. "g".
+const NATURALS: [i64; 7] = [9, 11, 0, 2, 4, 5, 7];
+
+pub fn intervals(name: &str) -> Option<&'static [i64]> {
+    SCALES.iter().find(|&&(k, _)| k == name).map(|&(_, v)| v)
+}
+
+// returns the notes of the scale on "root".  a heptatonic scale is ordered by the letters "a" ..
+// "g" spelling it with the fewest accidentals, so that it can replace the default scale.  the
+// others are in the ascending order from the root.
+pub fn build(intervals: &[i64], root: i64) -> Vec<i64> {
+    let notes: Vec<_> = intervals.iter().map(|&i| root + i).collect();
+    if notes.len() != NATURALS.len() {
+        return notes;
+    }
+
+    let distance = |x: i64, y: i64| {
+        let d = misc::imod(x - y, 12);
+        cmp::min(d, 12 - d)
+    };
+    let accidentals = |l: usize| {
+        let mut acc = 0;
+        for (k, &n) in notes.iter().enumerate() {
+            acc += distance(n, NATURALS[(l + k) % 7]);
+        }
+        acc
+    };
+    let letter = (0..7).min_by_key(|&l| accidentals(l)).unwrap();
+    let mut dst = vec![0; 7];
+    for (k, &n) in notes.iter().enumerate() {
+        dst[(letter + k) % 7] = n;
+    }
+    dst
+}
@@ -0,0 +1,84 @@
+// (c) Yasuhiro Fujii <http://mimosa-pudica.net>, under MIT License.
+use std::*;
+
+pub trait Provider {
+    fn read(&self, path: &path::Path) -> io::Result<String>;
+    // returns the path which identifies the same file uniquely.
+    fn canonicalize(&self, path: &path::Path) -> io::Result<path::PathBuf>;
+}
+
+pub struct FileProvider;
+
+impl Provider for FileProvider {
+    fn read(&self, path: &path::Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn canonicalize(&self, path: &path::Path) -> io::Result<path::PathBuf> {
+        fs::canonicalize(path)
+    }
+}
+
+// provides in-memory files, e.g. editor buffers, and optionally falls back to another provider.
+pub struct MemoryProvider<'a> {
+    files: collections::HashMap<path::PathBuf, String>,
+    fallback: Option<&'a dyn Provider>,
+}
+
+impl<'a> MemoryProvider<'a> {
+    pub fn new() -> Self {
+        MemoryProvider {
+            files: collections::HashMap::new(),
+            fallback: None,
+        }
+    }
+
+    pub fn with_fallback(fallback: &'a dyn Provider) -> Self {
+        MemoryProvider {
+            files: collections::HashMap::new(),
+            fallback: Some(fallback),
+        }
+    }
+
+    pub fn insert<T: AsRef<path::Path>, U: Into<String>>(&mut self, path: T, text: U) {
+        self.files.insert(normalize(path.as_ref()), text.into());
+    }
+}
+
+impl<'a> Provider for MemoryProvider<'a> {
+    fn read(&self, path: &path::Path) -> io::Result<String> {
+        match (self.files.get(&normalize(path)), self.fallback) {
+            (Some(v), _) => Ok(v.clone()),
+            (None, Some(f)) => f.read(path),
+            (None, None) => Err(io::Error::new(io::ErrorKind::NotFound, "file not found.")),
+        }
+    }
+
+    fn canonicalize(&self, path: &path::Path) -> io::Result<path::PathBuf> {
+        let key = normalize(path);
+        match (self.files.contains_key(&key), self.fallback) {
+            (true, _) => Ok(key),
+            (false, Some(f)) => f.canonicalize(path),
+            (false, None) => Err(io::Error::new(io::ErrorKind::NotFound, "file not found.")),
+        }
+    }
+}
+
+// removes "." and ".." lexically.
+fn normalize(path: &path::Path) -> path::PathBuf {
+    let mut dst = path::PathBuf::new();
+    for c in path.components() {
+        match c {
+            path::Component::CurDir => (),
+            path::Component::ParentDir => match dst.components().next_back() {
+                Some(path::Component::Normal(_)) => {
+                    dst.pop();
+                }
+                Some(path::Component::RootDir) | Some(path::Component::Prefix(_)) => (),
+                _ => dst.push(c),
+            },
+            _ => dst.push(c),
+        }
+    }
+    dst
+}
@@ -1,6 +1,44 @@
 // (c) Yasuhiro Fujii <http://mimosa-pudica.net>, under MIT License.
 use crate::chord;
+use crate::generator;
+use crate::lexer;
+use crate::misc;
 use crate::random;
+use crate::ratio;
+use crate::scale;
+use crate::source;
+use std::*;
+
+fn compile(files: &[(&str, &str)]) -> Result<crate::Assembly, misc::Diagnostics> {
+    let mut src = source::MemoryProvider::new();
+    for &(path, text) in files.iter() {
+        src.insert(path, text);
+    }
+    let rng = random::Generator::new(0);
+    crate::compile_from(&rng, &src, path::Path::new(files[0].0))
+}
+
+fn compile_notes(files: &[(&str, &str)]) -> Vec<(f64, f64, i64)> {
+    let asm = compile(files).unwrap();
+    let mut dst: Vec<_> = asm.channels[0]
+        .1
+        .score
+        .iter()
+        .filter_map(|f| f.nnum.map(|n| (f.t0.to_float(), f.t1.to_float(), n)))
+        .collect();
+    dst.sort_by(|x, y| x.partial_cmp(y).unwrap());
+    dst
+}
+
+fn compile_error(files: &[(&str, &str)]) -> String {
+    format!("{}", compile(files).unwrap_err())
+}
+
+fn assemble_warnings(files: &[(&str, &str)]) -> String {
+    let rng = random::Generator::new(0);
+    let (_, diags) = crate::assemble(&rng, &compile(files).unwrap());
+    format!("{}", diags)
+}
 
 fn test_chord(text: &str, rhs: &[isize]) {
     let (pos, mut lhs) = chord::parse(text);
@@ -283,3 +321,771 @@ fn test_voicing() {
         vec![7, 11, 12, 16]
     );
 }
+
+#[test]
+fn test_parameters() {
+    let src = "score $twice($x, $len) = [ stretch $len() $x() $x() ] score $out.0() = $twice({ c D }, 1/2)";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![(0.0, 0.25, 60), (0.25, 0.5, 62), (0.5, 1.0, 60), (1.0, 1.5, 62)]
+    );
+    let src = "score $f($x) = $x() score $out.0() = $f()";
+    assert_eq!(
+        compile_error(&[("main.mol", src)]),
+        "main.mol:1:38: error: wrong number of arguments."
+    );
+}
+
+#[test]
+fn test_let() {
+    let src = "score $out.0() = let $m($n) = transpose $n() { c } in [ $m(0) $m(2) ]";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![(0.0, 1.0, 60), (1.0, 2.0, 62)]
+    );
+    let src = "score $a() = let $x() = { c } in $x()\nscore $out.0() = [ $a() $x() ]";
+    assert_eq!(
+        compile_error(&[("main.mol", src)]),
+        "main.mol:2:25: error: undefined symbol."
+    );
+}
+
+#[test]
+fn test_import() {
+    let files = [
+        (
+            "main.mol",
+            "import \"lib/drums.mol\" as $drums\nscore $out.0() = [ $drums.fill() ]",
+        ),
+        (
+            "lib/drums.mol",
+            "import \"../common.mol\"\nscore $fill() = [ $kick() { c } ]",
+        ),
+        ("common.mol", "score $kick() = { E }"),
+    ];
+    assert_eq!(compile_notes(&files), vec![(0.0, 1.0, 64), (1.0, 2.0, 60)]);
+
+    let files = [("a.mol", "import \"b.mol\""), ("b.mol", "\nimport \"a.mol\"")];
+    assert_eq!(
+        compile_error(&files),
+        "b.mol:2:1: error: cyclic import: a.mol -> b.mol -> a.mol."
+    );
+
+    let files = [
+        ("a.mol", "import \"b.mol\"\nimport \"c.mol\""),
+        ("b.mol", "score $x() = { c }"),
+        ("c.mol", "score $x() = { c }"),
+    ];
+    assert_eq!(compile_error(&files), "a.mol:2:1: error: duplicated definition: $x.");
+}
+
+#[test]
+fn test_lexer() {
+    let src = "/* a /* b */ */ import \"/*.mol\" // c\n{ as0 } // d";
+    let (tokens, comments) = lexer::tokenize(path::Path::new("main.mol"), src).unwrap();
+    let toks: Vec<_> = tokens.iter().map(|t| t.tok).collect();
+    assert_eq!(
+        toks,
+        vec![
+            lexer::Tok::Keyword("import"),
+            lexer::Tok::Str("\"/*.mol\""),
+            lexer::Tok::Keyword("{"),
+            lexer::Tok::PitchLower('a'),
+            lexer::Tok::SymbolLower('s'),
+            lexer::Tok::Int("0"),
+            lexer::Tok::Keyword("}"),
+        ]
+    );
+    assert_eq!(tokens[0].comments, vec![(0, 15)]);
+    assert_eq!(tokens[2].comments, vec![(32, 36)]);
+    assert_eq!(comments, vec![(45, 49)]);
+    let err = lexer::tokenize(path::Path::new("main.mol"), "{ c } /* e").unwrap_err();
+    assert_eq!((err.bgn, err.message.as_str()), (6, "unterminated comment."));
+}
+
+#[test]
+fn test_multiple_errors() {
+    let src = "score $a() = { c ] }\nscore $b() = $x()\nscore $c( = { c }\nscore $out.0() = [ $a() $y() ]";
+    assert_eq!(
+        compile_error(&[("main.mol", src)]),
+        "main.mol:1:18: error: unexpected token.\n\
+         main.mol:3:11: error: unexpected token.\n\
+         main.mol:2:14: error: undefined symbol.\n\
+         main.mol:4:25: error: undefined symbol."
+    );
+}
+
+#[test]
+fn test_diagnostic_render() {
+    let src = "score $x() = { c }\nscore $x() = {\tc }";
+    let err = compile(&[("main.mol", src)]).unwrap_err();
+    assert_eq!(
+        err.render(false),
+        concat!(
+            "error: duplicated definition.\n",
+            " --> main.mol:2:7\n",
+            "  |\n",
+            "2 | score $x() = {\tc }\n",
+            "  |       ^^\n",
+            " --> main.mol:1:7\n",
+            "  |\n",
+            "1 | score $x() = { c }\n",
+            "  |       -- previous definition here.\n",
+        )
+    );
+
+    let src = "score $out.0() = {\t$ }";
+    let err = compile(&[("main.mol", src)]).unwrap_err();
+    assert_eq!(
+        err.render(false),
+        concat!(
+            "error: unexpected token.\n",
+            " --> main.mol:1:20\n",
+            "  |\n",
+            "1 | score $out.0() = {\t$ }\n",
+            "  |                   \t^\n",
+        )
+    );
+}
+
+#[test]
+fn test_warnings() {
+    let src = "score $out.0() = { c < < < < < < c }";
+    assert_eq!(
+        assemble_warnings(&[("main.mol", src)]),
+        "main.mol:1:34: warning: note number out of range: 132."
+    );
+    let src = "score $out.0() = { c (e e) }";
+    let rng = random::Generator::new(0);
+    let (_, diags) = crate::assemble(&rng, &compile(&[("main.mol", src)]).unwrap());
+    assert_eq!(
+        diags.render(false),
+        concat!(
+            "warning: overlapping notes of the same pitch.\n",
+            " --> main.mol:1:25\n",
+            "  |\n",
+            "1 | score $out.0() = { c (e e) }\n",
+            "  |                         ^\n",
+            " --> main.mol:1:23\n",
+            "  |\n",
+            "1 | score $out.0() = { c (e e) }\n",
+            "  |                       - overlapped note here.\n",
+        )
+    );
+    let src = "score $out.0() = { c d }\nvalue $out.0.velocity() = { 1 2 }\nvalue $out.begin() = 1/4";
+    assert_eq!(
+        assemble_warnings(&[("main.mol", src)]),
+        "main.mol:1:20: warning: note across the boundary of the output range is dropped.\n\
+         main.mol:1:22: warning: velocity out of range is clamped."
+    );
+    let src = "score $out.0() = shift -1/4 { c d }";
+    assert_eq!(
+        assemble_warnings(&[("main.mol", src)]),
+        "main.mol:1:31: warning: note before the beginning of the score is dropped."
+    );
+    let src = "score $out.0() = shift -1/4 { c d }\nvalue $out.begin() = 0 - 1/4";
+    assert_eq!(assemble_warnings(&[("main.mol", src)]), "");
+    let src = "score $out.0() = { c d }\nvalue $out.0.duration() = { 0 1 }";
+    assert_eq!(
+        assemble_warnings(&[("main.mol", src)]),
+        "main.mol:1:20: warning: note of non-positive length after offset and duration is dropped."
+    );
+}
+
+#[test]
+fn test_number_literals() {
+    use crate::generator;
+    use crate::ratio::Ratio;
+    assert_eq!("-3/8".parse::<Ratio>(), Ok(Ratio::new(-3, 8)));
+    assert_eq!("0.375".parse::<Ratio>(), Ok(Ratio::new(3, 8)));
+    assert_eq!("-2.50".parse::<Ratio>(), Ok(Ratio::new(-5, 2)));
+    assert_eq!("1/0".parse::<Ratio>(), Err("division by zero."));
+
+    let src = "score $out.0() = { c }\nvalue $out.0.pitch() = { (-1/4) 0.375 | (-1)..(-0.5) [(3/8) (-2)] }";
+    let asm = compile(&[("main.mol", src)]).unwrap();
+    let rng = random::Generator::new(0);
+    let evaluator = generator::Evaluator::new(&rng);
+    let values: Vec<_> = [0, 2, 4, 5, 6, 7]
+        .iter()
+        .map(|&t| evaluator.eval(&asm.channels[0].1.pitch, Ratio::new(t, 4)))
+        .collect();
+    assert_eq!(values, vec![-0.25, 0.375, -1.0, -0.75, 0.375, -2.0]);
+
+    // the spellings without parentheses keep their meanings: "/" is a repeat and "-" is a flat.
+    let src = "score $out.0() = { c }\nvalue $out.0.pitch() = { 3/8 }";
+    let asm = compile(&[("main.mol", src)]).unwrap();
+    let values: Vec<_> = [0, 1, 2]
+        .iter()
+        .map(|&t| evaluator.eval(&asm.channels[0].1.pitch, Ratio::new(t, 3)))
+        .collect();
+    assert_eq!(values, vec![3.0, 3.0, 8.0]);
+    assert_eq!(
+        compile_notes(&[("main.mol", "score $out.0() = stretch 0.5 { c- c }")]),
+        vec![(0.0, 0.25, 59), (0.25, 0.5, 48)]
+    );
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = { c -1 }")]),
+        "main.mol:1:23: error: syntax error."
+    );
+
+    // a parenthesized value after a curve is an endpoint, as in the tutorial.
+    let src = "score $out.0() = { c | c }\nvalue $out.tempo() = { (1/2) | (1/2)..{exp}(1/3) }";
+    let asm = compile(&[("main.mol", src)]).unwrap();
+    let values: Vec<_> = [0, 1, 2]
+        .iter()
+        .map(|&t| evaluator.eval(&asm.tempo, Ratio::new(t, 1)))
+        .collect();
+    assert_eq!(values, vec![0.5, 0.5, 1.0 / 3.0]);
+}
+
+#[test]
+fn test_named_symbols() {
+    let src = "score $out.0() = { {bass}0 {Chord}1 q0 {bass}1 } with $bass = { (c E) } with $chord = { (e G B) } \
+               with q = { G }";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![(0.0, 0.25, 60), (0.25, 0.5, 67), (0.5, 0.75, 55), (0.75, 1.0, 52)]
+    );
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = { c } with $Q = { c }")]),
+        "main.mol:1:29: error: symbol name must begin with a lowercase letter."
+    );
+}
+
+#[test]
+fn test_negative_ordinals() {
+    let src = "score $out.0() = { Q-1 q-2 q0 | Q-1 q-3 } with q = { (c G E) | (d F A C) }";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![
+            (0.0, 1.0 / 3.0, 64),
+            (1.0 / 3.0, 2.0 / 3.0, 55),
+            (2.0 / 3.0, 1.0, 48),
+            (1.0, 1.5, 60),
+            (1.5, 2.0, 53)
+        ]
+    );
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = { q-4 } with q = { (c E G) }")]),
+        "main.mol:1:20: error: note does not exist."
+    );
+}
+
+#[test]
+fn test_score_branch_and_choose() {
+    let src = "score $out.0() = repeat 3 if $time() < 1 || $time() >= 2 then { c } else { D }";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![(0.0, 1.0, 60), (1.0, 2.0, 62), (2.0, 3.0, 60)]
+    );
+
+    let src = "score $out.0() = repeat 16 choose( { c }, { D }, { _ } )";
+    let notes = compile_notes(&[("main.mol", src)]);
+    assert_eq!(notes, compile_notes(&[("main.mol", src)]));
+    assert!(notes.iter().all(|&(t0, t1, n)| t1 == t0 + 1.0 && (n == 60 || n == 62)));
+    assert!(notes.iter().any(|&(_, _, n)| n == 60) && notes.iter().any(|&(_, _, n)| n == 62));
+    assert!(notes.len() < 16);
+}
+
+#[test]
+fn test_repeat_index_and_volta() {
+    let src = "score $out.0() = repeat 3 [ { c } volta( { D }, { E } ) ]";
+    let notes: Vec<_> = compile_notes(&[("main.mol", src)]).iter().map(|&(_, _, n)| n).collect();
+    assert_eq!(notes, vec![60, 62, 60, 62, 60, 64]);
+
+    let src = "score $out.0() = repeat 2 repeat 2 transpose $repeat.i() + $repeat.n() { c }";
+    let notes: Vec<_> = compile_notes(&[("main.mol", src)]).iter().map(|&(_, _, n)| n).collect();
+    assert_eq!(notes, vec![62, 63, 62, 63]);
+
+    let src = "score $out.0() = repeat 2 stretch $r() { c }\nvalue $r() = $repeat.i() + $repeat.n() - 1";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![(0.0, 1.0, 60), (1.0, 3.0, 60)]
+    );
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = stretch $time() { c }")]),
+        "main.mol:1:26: error: constant expected."
+    );
+
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = [ { c } volta( { D }, { E } ) ]")]),
+        "main.mol:1:26: error: volta outside repeat."
+    );
+}
+
+#[test]
+fn test_reverse_invert_rotate() {
+    let src = "score $out.0() = [ { c } reverse { D E^ | E _ } ]";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![(0.0, 1.0, 60), (1.5, 2.5, 64), (2.5, 3.0, 62)]
+    );
+    let src = "score $out.0() = invert 62.5 { c E G }";
+    let notes: Vec<_> = compile_notes(&[("main.mol", src)]).iter().map(|&(_, _, n)| n).collect();
+    assert_eq!(notes, vec![65, 61, 58]);
+    let src = "score $out.0() = invert {D} { c E G }";
+    let notes: Vec<_> = compile_notes(&[("main.mol", src)]).iter().map(|&(_, _, n)| n).collect();
+    assert_eq!(notes, vec![64, 60, 57]);
+    let src = "score $out.0() = invert { c | D } { c E | G c }";
+    let notes: Vec<_> = compile_notes(&[("main.mol", src)]).iter().map(|&(_, _, n)| n).collect();
+    assert_eq!(notes, vec![60, 56, 57, 64]);
+    let src = "score $out.0() = rotate 5/4 { c D E^ | E }";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![
+            (0.0, 1.25, 64),
+            (1.25, 19.0 / 12.0, 60),
+            (19.0 / 12.0, 23.0 / 12.0, 62),
+            (23.0 / 12.0, 2.0, 64)
+        ]
+    );
+}
+
+#[test]
+fn test_shift_and_fit() {
+    let src = "score $out.0() = [ ( { c D } shift 1/4 { G } ) { c } ]";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![(0.0, 0.5, 60), (0.25, 1.25, 67), (0.5, 1.0, 62), (1.0, 2.0, 60)]
+    );
+    let src = "score $out.0() = [ fit 1 [ { c D } { E } repeat 2 { F } ] { c } ]";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![
+            (0.0, 0.125, 60),
+            (0.125, 0.25, 62),
+            (0.25, 0.5, 64),
+            (0.5, 0.75, 65),
+            (0.75, 1.0, 65),
+            (1.0, 2.0, 60)
+        ]
+    );
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = fit 1 []")]),
+        "main.mol:1:18: error: score of zero length."
+    );
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = fit 0 { c }")]),
+        "main.mol:1:22: error: non-positive length."
+    );
+
+    // the score is regenerated, so the trill follows the stretch.
+    let src = "score $out.0() = ornament 1/4 fit 1 { c~ D }";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![(0.0, 0.25, 60), (0.25, 0.5, 62), (0.5, 1.0, 62)]
+    );
+}
+
+#[test]
+fn test_dtranspose() {
+    let src = "score $out.0() = dtranspose 2 { c D E F+ B }";
+    let notes: Vec<_> = compile_notes(&[("main.mol", src)]).iter().map(|&(_, _, n)| n).collect();
+    assert_eq!(notes, vec![64, 65, 67, 70, 74]);
+    let src = "score $out.0() = dtranspose q { (-1) } { c E G } with q = { (d F A) }";
+    let notes: Vec<_> = compile_notes(&[("main.mol", src)]).iter().map(|&(_, _, n)| n).collect();
+    assert_eq!(notes, vec![56, 59, 64]);
+
+    // the example in the tutorial, where the scale is bound to the whole time.
+    let src = concat!(
+        "score $a_major() = { (c+DEF+G+AB) }\n",
+        "score $motif()   = { A B C D }\n",
+        "score $out.0()   = [ $motif() dtranspose 1 $motif() dtranspose 2 $motif() ] with * = repeat 3 $a_major()",
+    );
+    let notes: Vec<_> = compile_notes(&[("main.mol", src)]).iter().map(|&(_, _, n)| n).collect();
+    assert_eq!(notes, vec![61, 62, 64, 66, 62, 64, 66, 68, 64, 66, 68, 69]);
+}
+
+#[test]
+fn test_scales_and_key() {
+    let major = scale::intervals("major").unwrap();
+    assert_eq!(scale::build(major, 63), vec![68, 70, 72, 74, 63, 65, 67]);
+    assert_eq!(scale::build(major, 66), vec![70, 71, 73, 75, 77, 66, 68]);
+    assert!(scale::intervals("unknown").is_none());
+
+    let src = "key = $scale.major({ d })\nscore $out.0() = { c F }";
+    let notes: Vec<_> = compile_notes(&[("main.mol", src)]).iter().map(|&(_, _, n)| n).collect();
+    assert_eq!(notes, vec![49, 54]);
+
+    let src = "key = [ $scale.major({ c }) $scale.major({ f }) ]\nscore $out.0() = repeat 3 { b }";
+    let notes: Vec<_> = compile_notes(&[("main.mol", src)]).iter().map(|&(_, _, n)| n).collect();
+    assert_eq!(notes, vec![59, 58, 58]);
+
+    // "key" does not collide with "$key()" and the definitions precede the built-in scales.
+    let src = "key = $scale.major({ d })\nscore $key() = { c }\nscore $out.0() = [ $key() { c } ]";
+    let notes: Vec<_> = compile_notes(&[("main.mol", src)]).iter().map(|&(_, _, n)| n).collect();
+    assert_eq!(notes, vec![49, 49]);
+    let src = "score $scale.major($root) = { E }\nscore $out.0() = $scale.major({ d })";
+    let notes: Vec<_> = compile_notes(&[("main.mol", src)]).iter().map(|&(_, _, n)| n).collect();
+    assert_eq!(notes, vec![64]);
+
+    let src = "score $out.0() = { q0 Q1 Q5 q1 } with q = $scale.whole_tone({ c })";
+    let notes: Vec<_> = compile_notes(&[("main.mol", src)]).iter().map(|&(_, _, n)| n).collect();
+    assert_eq!(notes, vec![60, 62, 70, 62]);
+
+    assert_eq!(
+        compile_error(&[("main.mol", "key = $scale.major({ _ })\nscore $out.0() = { c }")]),
+        "main.mol:1:7: error: key without notes."
+    );
+}
+
+#[test]
+fn test_arpeggio() {
+    let src = "score $out.0() = arpeggio 1/8 { (c E G) }";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![(0.0, 1.0, 60), (0.125, 1.0, 64), (0.25, 1.0, 67)]
+    );
+    let src = "score $out.0() = arpeggio updown 1/8 { (c E G) (c E G) }";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![
+            (0.0, 0.5, 60),
+            (0.125, 0.5, 64),
+            (0.25, 0.5, 67),
+            (0.5, 1.0, 67),
+            (0.625, 1.0, 64),
+            (0.75, 1.0, 60)
+        ]
+    );
+    let src = "score $out.0() = arpeggio random $time() / 4 { _ (c E G) }";
+    let notes = compile_notes(&[("main.mol", src)]);
+    let mut t0s: Vec<_> = notes.iter().map(|&(t0, _, _)| t0).collect();
+    t0s.sort_by(|x, y| x.partial_cmp(y).unwrap());
+    assert_eq!(t0s, vec![0.5, 0.625, 0.75]);
+
+    // a constant delay is exact and a delay longer than the chord is limited.
+    let src = "score $out.0() = arpeggio 1/1000 { (c E G) }";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![(0.0, 1.0, 60), (0.001, 1.0, 64), (0.002, 1.0, 67)]
+    );
+    let src = "score $out.0() = arpeggio 10 { (c E G) }";
+    let asm = compile(&[("main.mol", src)]).unwrap();
+    let t0s: Vec<_> = asm.channels[0].1.score.iter().map(|f| f.t0 * 3).collect();
+    assert_eq!(t0s, vec![0.into(), 1.into(), 2.into()]);
+    let rng = random::Generator::new(0);
+    let (events, _) = crate::assemble(&rng, &asm);
+    assert_eq!(events.len(), 6);
+}
+
+#[test]
+fn test_grace_notes_and_ornaments() {
+    let src = "score $out.0() = { c d'C [F e]''D }";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![
+            (0.0, 1.0 / 3.0, 60),
+            (1.0 / 3.0 - 1.0 / 32.0, 1.0 / 3.0, 50),
+            (1.0 / 3.0, 2.0 / 3.0, 60),
+            (2.0 / 3.0, 2.0 / 3.0 + 1.0 / 32.0, 65),
+            (2.0 / 3.0 + 1.0 / 32.0, 2.0 / 3.0 + 1.0 / 16.0, 64),
+            (2.0 / 3.0 + 1.0 / 16.0, 1.0, 74)
+        ]
+    );
+    let src = "score $out.0() = ornament 1/8 { c~ | E~+ B~- }";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![
+            (0.0, 0.125, 60),
+            (0.125, 0.25, 62),
+            (0.25, 0.375, 60),
+            (0.375, 0.5, 62),
+            (0.5, 0.625, 60),
+            (0.625, 0.75, 62),
+            (0.75, 0.875, 60),
+            (0.875, 1.0, 62),
+            (1.0, 1.125, 64),
+            (1.125, 1.25, 65),
+            (1.25, 1.5, 64),
+            (1.5, 1.625, 71),
+            (1.625, 1.75, 69),
+            (1.75, 2.0, 71)
+        ]
+    );
+
+    // a tied note is ornamented as a whole and a grace note before the score is reported.
+    let src = "score $out.0() = ornament 1/4 { c~+^ | c }";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![(0.0, 0.25, 60), (0.25, 0.5, 62), (0.5, 2.0, 60)]
+    );
+    assert_eq!(
+        assemble_warnings(&[("main.mol", "score $out.0() = { d'c }")]),
+        "main.mol:1:20: warning: note before the beginning of the score is dropped."
+    );
+
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = ornament 0 { c~ }")]),
+        "main.mol:1:27: error: non-positive length."
+    );
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = ornament 1/100000 { c~ }")]),
+        "main.mol:1:38: error: too many divisions."
+    );
+}
+
+#[test]
+fn test_ratchet_and_tremolo() {
+    let src = "score $out.0() = { c%4 / | E%2^ E }";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![
+            (0.0, 0.125, 60),
+            (0.125, 0.25, 60),
+            (0.25, 0.375, 60),
+            (0.375, 0.5, 60),
+            (0.5, 0.625, 60),
+            (0.625, 0.75, 60),
+            (0.75, 0.875, 60),
+            (0.875, 1.0, 60),
+            (1.0, 1.25, 64),
+            (1.25, 2.0, 64)
+        ]
+    );
+    let src = "score $out.0() = ornament 1/8 { c%%E G }";
+    assert_eq!(
+        compile_notes(&[("main.mol", src)]),
+        vec![
+            (0.0, 0.125, 60),
+            (0.125, 0.25, 64),
+            (0.25, 0.375, 60),
+            (0.375, 0.5, 64),
+            (0.5, 1.0, 67)
+        ]
+    );
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = { c%0 }")]),
+        "main.mol:1:23: error: zero length group."
+    );
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = { c%4096 }")]),
+        "main.mol:1:20: error: too many divisions."
+    );
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = ornament 0 { c%%E }")]),
+        "main.mol:1:27: error: non-positive length."
+    );
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = ornament 1/100000 { c%%E }")]),
+        "main.mol:1:38: error: too many divisions."
+    );
+}
+
+#[test]
+fn test_articulations_and_dynamics() {
+    let src = "score $out.0() = { \\pp c D! \\f E. F= }\nvalue $out.0.duration() = { (1/8) }";
+    let rng = random::Generator::new(0);
+    let (events, _) = crate::assemble(&rng, &compile(&[("main.mol", src)]).unwrap());
+    let dst: Vec<_> = events.iter().map(|e| (e.time, e.msg)).collect();
+    assert_eq!(
+        dst,
+        vec![
+            (0.0, [0x90, 60, 32]),
+            (0.25, [0x80, 60, 32]),
+            (0.5, [0x90, 62, 48]),
+            (0.75, [0x80, 62, 48]),
+            (1.0, [0x90, 64, 95]),
+            (1.125, [0x80, 64, 95]),
+            (1.5, [0x90, 65, 95]),
+            (2.0, [0x80, 65, 95])
+        ]
+    );
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = { \\fp c }")]),
+        "main.mol:1:20: error: unknown dynamic."
+    );
+}
+
+#[test]
+fn test_hairpins() {
+    let src = "score $out.0() = { \\p c D \\< E F | \\f G }\nvalue $out.0.cc11() = $dynamic($out.0())";
+    let asm = compile(&[("main.mol", src)]).unwrap();
+    let ch = &asm.channels[0].1;
+    let dst: Vec<_> = ch.score.iter().map(|f| f.marks.dynamic.unwrap().to_float()).collect();
+    assert_eq!(dst, vec![0.6, 0.6, 0.6, 0.9, 1.2]);
+    let rng = random::Generator::new(0);
+    let evaluator = generator::Evaluator::new(&rng);
+    let dst: Vec<_> = [(0, 1), (1, 4), (5, 8), (7, 8), (2, 1)]
+        .iter()
+        .map(|&(n, d)| evaluator.eval(&ch.ccs[0].1, ratio::Ratio::new(n, d)))
+        .collect();
+    assert_eq!(dst, vec![0.6, 0.6, 0.75, 1.05, 1.2]);
+
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = { c \\< D }")]),
+        "main.mol:1:22: error: unterminated hairpin."
+    );
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = { \\f c \\< D \\p E }")]),
+        "main.mol:1:25: error: hairpin in the wrong direction."
+    );
+}
+
+#[test]
+fn test_value_curves() {
+    let src = concat!(
+        "score $out.0() = { c }\n",
+        "value $out.0.cc1() = { 1..{exp}4 | 0..{step}1 | 0..{smooth}1 | 1..{log}4 | 0..{bezier}(1)1 | 2..{exp}* | 8 }",
+    );
+    let asm = compile(&[("main.mol", src)]).unwrap();
+    let rng = random::Generator::new(0);
+    let evaluator = generator::Evaluator::new(&rng);
+    let dst: Vec<_> = [(1, 2), (3, 2), (9, 4), (7, 2), (17, 4), (11, 2), (13, 2)]
+        .iter()
+        .map(|&(n, d)| evaluator.eval(&asm.channels[0].1.ccs[0].1, ratio::Ratio::new(n, d)))
+        .collect();
+    assert_eq!(dst, vec![2.0, 0.0, 0.15625, 3.0, 0.15625, 4.0, 8.0]);
+
+    assert_eq!(
+        compile_error(&[("main.mol", "score $out.0() = { c }\nvalue $out.0.cc1() = { 0..{exp}1 }")]),
+        "main.mol:2:24: error: exponential curve across zero."
+    );
+    assert_eq!(
+        compile_error(&[(
+            "main.mol",
+            "score $out.0() = { c }\nvalue $out.0.cc1() = { 0..{sine}1 }"
+        )]),
+        "main.mol:2:27: error: unknown curve."
+    );
+
+    // a parenthesized value after a curve without a parameter is an endpoint.
+    let src = concat!(
+        "score $out.0() = { c }\n",
+        "value $out.0.cc1() = { (-2)..{exp}(-1/2) | 0..{step}(1/2) | (-1/2)..{log}(-2) | (1/2)..{smooth}(-1/2) | 8 }",
+    );
+    let asm = compile(&[("main.mol", src)]).unwrap();
+    let dst: Vec<_> = [(1, 2), (3, 2), (5, 2), (7, 2), (13, 4)]
+        .iter()
+        .map(|&(n, d)| evaluator.eval(&asm.channels[0].1.ccs[0].1, ratio::Ratio::new(n, d)))
+        .collect();
+    assert_eq!(dst, vec![-1.0, 0.0, -1.5, 0.0, 0.34375]);
+    assert_eq!(
+        compile_error(&[(
+            "main.mol",
+            "score $out.0() = { c }\nvalue $out.0.cc1() = { 0..{exp}(1)1 }"
+        )]),
+        "main.mol:2:27: error: curve with an unexpected parameter."
+    );
+    assert_eq!(
+        compile_error(&[(
+            "main.mol",
+            "score $out.0() = { c }\nvalue $out.0.cc1() = { 0..{bezier}1 }"
+        )]),
+        "main.mol:2:27: error: curve without a parameter."
+    );
+}
+
+#[test]
+fn test_value_functions() {
+    let cases = [
+        ("$sin(1, 1/4)", (0, 1), 1.0),
+        ("$tri(1)", (1, 4), 1.0),
+        ("$tri(1)", (5, 8), -0.5),
+        ("$saw(1)", (1, 4), 0.5),
+        ("$saw(1, 1/2)", (1, 4), -0.5),
+        ("$square(2)", (1, 8), 1.0),
+        ("$square(2)", (3, 8), -1.0),
+        ("$min($time(), 1/2)", (3, 4), 0.5),
+        ("$max($time(), 1/2)", (3, 4), 0.75),
+        ("$abs({ (-1/2) })", (0, 1), 0.5),
+        ("$floor({ (-1/2) })", (0, 1), -1.0),
+        ("$clamp($time() * 2, 0, 1)", (3, 4), 1.0),
+        ("$pow(2, 3)", (0, 1), 8.0),
+        ("$exp(0)", (0, 1), 1.0),
+        ("$mod($time(), 1/2)", (3, 4), 0.25),
+        ("$mod(3, 0)", (0, 1), 3.0),
+        ("$pow(0 - 8, 1/3)", (0, 1), 0.0),
+    ];
+    let mut src = String::from("score $out.0() = { c }\n");
+    for (i, &(expr, _, _)) in cases.iter().enumerate() {
+        src.push_str(&format!("value $out.0.cc{}() = {}\n", i, expr));
+    }
+    let asm = compile(&[("main.mol", &src)]).unwrap();
+    let rng = random::Generator::new(0);
+    let evaluator = generator::Evaluator::new(&rng);
+    for (i, &(expr, (n, d), v)) in cases.iter().enumerate() {
+        let ir = &asm.channels[0].1.ccs[i].1;
+        assert_eq!(evaluator.eval(ir, ratio::Ratio::new(n, d)), v, "{}", expr);
+    }
+
+    // the definitions precede the built-in functions.
+    let src = "score $out.0() = { c }\nvalue $min($a, $b) = $a() + $b()\nvalue $out.0.cc0() = $min(1, 2)";
+    let asm = compile(&[("main.mol", src)]).unwrap();
+    assert_eq!(evaluator.eval(&asm.channels[0].1.ccs[0].1, ratio::Ratio::zero()), 3.0);
+
+    let asm = compile(&[("main.mol", "score $out.0() = { c }\nvalue $out.0.cc0() = $rand()")]).unwrap();
+    for i in 0..16 {
+        let v = evaluator.eval(&asm.channels[0].1.ccs[0].1, ratio::Ratio::new(i, 4));
+        assert!((0.0..1.0).contains(&v));
+    }
+}
+
+#[test]
+fn test_note_context_values() {
+    let src = concat!(
+        "score $out.0() = { c (E G) A | c }\n",
+        "value $out.0.cc0() = $note.pitch()\n",
+        "value $out.0.cc1() = $note.prev_pitch()\n",
+        "value $out.0.cc2() = $note.next_pitch()\n",
+        "value $out.0.cc3() = $note.top()\n",
+        "value $out.0.cc4() = $note.bottom()\n",
+        "value $out.0.cc5() = $note.time()\n",
+        "value $out.0.cc6() = $beat()\n",
+        "value $out.0.cc7() = $bar()\n",
+    );
+    let asm = compile(&[("main.mol", src)]).unwrap();
+    let ch = &asm.channels[0].1;
+    let rng = random::Generator::new(0);
+    let mut evaluator = generator::Evaluator::new(&rng);
+    evaluator.set_score(&ch.score);
+    let mut dst = Vec::new();
+    for f in ch.score.iter() {
+        evaluator.set_note(&ch.score, f);
+        let vs: Vec<_> = ch.ccs[..5].iter().map(|e| evaluator.eval(&e.1, f.t0)).collect();
+        dst.push((f.t0, vs));
+    }
+    dst.sort_by(|x, y| x.partial_cmp(y).unwrap());
+    let dst: Vec<_> = dst.into_iter().map(|e| e.1).collect();
+    assert_eq!(
+        dst,
+        vec![
+            vec![60.0, 60.0, 67.0, 1.0, 1.0],
+            vec![64.0, 60.0, 69.0, 0.0, 1.0],
+            vec![67.0, 60.0, 69.0, 1.0, 0.0],
+            vec![69.0, 67.0, 60.0, 1.0, 1.0],
+            vec![60.0, 69.0, 60.0, 1.0, 1.0]
+        ]
+    );
+
+    let f = ch.score.iter().find(|f| f.t0 == ratio::Ratio::one()).unwrap();
+    evaluator.set_note(&ch.score, f);
+    let t = ratio::Ratio::new(5, 4);
+    let dst: Vec<_> = ch.ccs[5..].iter().map(|e| evaluator.eval(&e.1, t)).collect();
+    assert_eq!(dst, vec![0.25, 0.25, 1.0]);
+
+    // "$note.velocity()" is given by the marks in the velocity and by the velocity in the others.
+    let src = "score $out.0() = { \\f c c! }\nvalue $out.0.cc0() = $note.velocity()";
+    let asm = compile(&[("main.mol", src)]).unwrap();
+    let ch = &asm.channels[0].1;
+    evaluator.set_score(&ch.score);
+    let mut dst = Vec::new();
+    for f in ch.score.iter() {
+        evaluator.set_note(&ch.score, f);
+        dst.push(evaluator.eval(&ch.ccs[0].1, f.t0));
+    }
+    assert_eq!(dst, vec![0.75, 0.875]);
+    let src = "score $out.0() = { c d }\nvalue $out.0.velocity() = { 1 0 }\n\
+               value $out.0.duration() = $note.len() * $note.velocity()";
+    assert_eq!(
+        assemble_warnings(&[("main.mol", src)]),
+        "main.mol:1:22: warning: note of non-positive length after offset and duration is dropped."
+    );
+
+    // the control changes follow the latest note, so "$note.time()" gives an envelope of each note.
+    let src = "score $out.0() = { c D }\nvalue $out.0.cc1() = $note.time() * 2";
+    let (events, _) = crate::assemble(&rng, &compile(&[("main.mol", src)]).unwrap());
+    let dst: Vec<_> = events.iter().filter(|e| e.msg[0] == 0xb0).map(|e| e.msg[2]).collect();
+    let i = dst.iter().rposition(|&v| v == 0).unwrap();
+    assert_eq!((dst[0], dst[i - 1], dst[dst.len() - 1]), (0, 126, 126));
+}
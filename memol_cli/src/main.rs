@@ -11,6 +11,8 @@ struct ArgOptions {
     verbose: bool,
     #[options(help = "Generate a MIDI file and exit.")]
     batch: bool,
+    #[options(no_short, help = "Colorize diagnostics.")]
+    color: bool,
     #[options(help = "Use JACK.")]
     jack: bool,
     #[options(help = "Use plugins.")]
@@ -21,24 +23,29 @@ struct ArgOptions {
     connect: Vec<String>,
 }
 
-fn compile(path: &path::Path, verbose: bool) -> Option<Vec<memol::midi::Event>> {
+fn compile(path: &path::Path, verbose: bool, color: bool) -> Option<Vec<memol::midi::Event>> {
     let timer = time::Instant::now();
     let rng = memol::random::Generator::new(0);
-    let result = memol::compile(&rng, &path).and_then(|e| memol::assemble(&rng, &e));
+    let result = memol::compile(&rng, &path).map(|e| memol::assemble(&rng, &e));
     let elapsed = timer.elapsed();
     if verbose {
         eprintln!(
             "compile time: {} ms",
             elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000
         );
-        eprintln!(" event count: {}", result.as_ref().map(|evs| evs.len()).unwrap_or(0));
+        eprintln!(" event count: {}", result.as_ref().map(|e| e.0.len()).unwrap_or(0));
     }
     match result {
         Err(e) => {
-            println!("{}", e);
+            println!("{}", e.render(color));
             None
         }
-        Ok(v) => Some(v),
+        Ok((evs, warnings)) => {
+            if !warnings.is_empty() {
+                println!("{}", warnings.render(color));
+            }
+            Some(evs)
+        }
     }
 }
 
@@ -55,7 +62,7 @@ fn main() {
 
         // generate MIDI file.
         if opts.batch {
-            if let Some(events) = compile(&opts.file, opts.verbose) {
+            if let Some(events) = compile(&opts.file, opts.verbose, opts.color) {
                 let smf = memol::smf::generate_smf(&events, 480);
                 fs::write(opts.file.with_extension("mid"), smf)?;
             }
@@ -88,7 +95,7 @@ fn main() {
 
         // main loop.
         loop {
-            if let Some(events) = compile(&opts.file, opts.verbose) {
+            if let Some(events) = compile(&opts.file, opts.verbose, opts.color) {
                 let bgn = match events.get(0) {
                     Some(ev) => ev.time.max(0.0),
                     None => 0.0,
@@ -16,6 +16,7 @@ pub struct CompileThread {
     rx: sync::mpsc::Receiver<Message>,
     on_success: Box<dyn FnMut(path::PathBuf, Assembly, Vec<midi::Event>) + marker::Send>,
     on_failure: Box<dyn FnMut(String) + marker::Send>,
+    on_warning: Box<dyn FnMut(String) + marker::Send>,
 }
 
 impl CompileThread {
@@ -26,6 +27,7 @@ impl CompileThread {
             rx: rx,
             on_success: Box::new(|_, _, _| ()),
             on_failure: Box::new(|_| ()),
+            on_warning: Box::new(|_| ()),
         }
     }
 
@@ -37,6 +39,10 @@ impl CompileThread {
         self.on_failure = Box::new(f);
     }
 
+    pub fn on_warning<T: 'static + FnMut(String) + marker::Send>(&mut self, f: T) {
+        self.on_warning = Box::new(f);
+    }
+
     pub fn create_sender(&self) -> sync::mpsc::Sender<Message> {
         self.tx.clone()
     }
@@ -71,14 +77,11 @@ impl CompileThread {
                         continue;
                     }
                 };
-                let evs = match assemble(&rng, &asm) {
-                    Ok(v) => v,
-                    Err(e) => {
-                        (self.on_failure)(format!("{}", e));
-                        continue;
-                    }
-                };
+                let (evs, diags) = assemble(&rng, &asm);
                 (self.on_success)(path.clone(), asm, evs);
+                if !diags.is_empty() {
+                    (self.on_warning)(format!("{}", diags));
+                }
             }
         })
     }
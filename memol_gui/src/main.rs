@@ -211,6 +211,13 @@ fn main() {
                 window_tx.send_event(UiMessage::Text(text)).unwrap();
             }
         });
+        // the warnings are shown after the data, which clears the text.
+        compiler.on_warning({
+            let window_tx = window.create_proxy();
+            move |text| {
+                window_tx.send_event(UiMessage::Text(text)).unwrap();
+            }
+        });
         if let Some(path) = opts.file {
             compiler
                 .create_sender()